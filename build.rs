@@ -0,0 +1,30 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let lockfile = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.lock");
+
+    println!("cargo:rerun-if-changed={}", lockfile.display());
+
+    let version = fs::read_to_string(&lockfile)
+        .ok()
+        .and_then(|lock| package_version(&lock, "niri-ipc"))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=PIRI_NIRI_IPC_VERSION={version}");
+}
+
+/// Finds the version of a package in the contents of a Cargo.lock file.
+fn package_version(lock: &str, name: &str) -> Option<String> {
+    let mut lines = lock.lines();
+
+    while let Some(line) = lines.next() {
+        if line.trim() == format!("name = \"{name}\"") {
+            let version = lines.next()?.trim().strip_prefix("version = ")?;
+            return Some(version.trim_matches('"').to_string());
+        }
+    }
+
+    None
+}
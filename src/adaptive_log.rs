@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{Event, Level, Subscriber, info};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::reload::Handle;
+
+/// Warnings within [`ERROR_WINDOW`] that raise the log level.
+const ERROR_THRESHOLD: usize = 3;
+const ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+/// Time without warnings after which the log level is restored.
+const QUIET_PERIOD: Duration = Duration::from_secs(300);
+
+/// Logs at debug level for a while whenever warnings and errors pile up, see `--adaptive-logging`.
+///
+/// The layer only notices the warnings, the filter is reloaded from a thread of its own since
+/// reloading it while an event is being logged could deadlock. It has to sit outside of that
+/// filter, or a level above warnings would hide them.
+pub struct AdaptiveLog {
+    warnings: Sender<Instant>,
+}
+
+impl AdaptiveLog {
+    /// Starts watching for warnings, reloading the filter behind `handle`. `normal` builds the
+    /// filter restored once things calmed down.
+    pub fn new<S>(
+        handle: Handle<EnvFilter, S>,
+        normal: impl Fn() -> EnvFilter + Send + 'static,
+    ) -> Self
+    where
+        S: 'static,
+    {
+        let (warnings, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut recent: VecDeque<Instant> = VecDeque::new();
+            let mut raised = false;
+
+            loop {
+                let timeout = if raised { QUIET_PERIOD } else { ERROR_WINDOW };
+
+                match receiver.recv_timeout(timeout) {
+                    Ok(at) => {
+                        recent.push_back(at);
+                        while recent
+                            .front()
+                            .is_some_and(|&first| at.duration_since(first) > ERROR_WINDOW)
+                        {
+                            recent.pop_front();
+                        }
+
+                        if !raised && recent.len() >= ERROR_THRESHOLD {
+                            let debug = EnvFilter::builder()
+                                .with_default_directive(LevelFilter::DEBUG.into())
+                                .parse_lossy("");
+                            if handle.reload(debug).is_ok() {
+                                raised = true;
+                                info!(
+                                    "{} warnings in the last {:?}, logging at debug level until things calm down",
+                                    recent.len(),
+                                    ERROR_WINDOW
+                                );
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) if raised => {
+                        if handle.reload(normal()).is_ok() {
+                            raised = false;
+                            recent.clear();
+                            info!("No warnings for {:?}, restored the log level", QUIET_PERIOD);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => recent.clear(),
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+
+        Self { warnings }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for AdaptiveLog {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() <= Level::WARN {
+            let _ = self.warnings.send(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tracing::warn;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::reload;
+
+    use super::*;
+
+    fn error_filter() -> EnvFilter {
+        EnvFilter::builder()
+            .with_default_directive(LevelFilter::ERROR.into())
+            .parse_lossy("")
+    }
+
+    #[test]
+    fn warnings_below_the_log_level_raise_it() {
+        let (filter, handle) = reload::Layer::new(error_filter());
+        let adaptive_log = AdaptiveLog::new(handle.clone(), error_filter);
+        let subscriber = tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(io::sink)
+                    .with_filter(filter),
+            )
+            .with(adaptive_log.with_filter(LevelFilter::WARN));
+
+        // The handle only lives as long as the subscriber
+        let _default = tracing::subscriber::set_default(subscriber);
+        for _ in 0..ERROR_THRESHOLD {
+            warn!("Something went wrong");
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let raised = || {
+            handle
+                .with_current(|filter| filter.max_level_hint() == Some(LevelFilter::DEBUG))
+                .unwrap()
+        };
+        while !raised() {
+            assert!(Instant::now() < deadline, "the log level wasn't raised");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
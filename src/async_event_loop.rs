@@ -0,0 +1,148 @@
+//! The event loop on tokio, waiting for events, commands and timers at once.
+
+use std::future;
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Instant;
+
+use anyhow::Result;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time;
+use tracing::warn;
+
+use crate::control::{self, ControlRequest};
+use crate::event_loop::{self, Reconnect, SessionEnd, SessionTimers};
+use crate::ipc::{EventDump, Socket};
+use crate::log_connect_step;
+use crate::state::State;
+
+/// Handles events from niri until a limit is reached, reconnecting whenever the connection drops.
+///
+/// Requests to niri are still blocking round trips over the local socket, only waiting for
+/// events, commands and timers is asynchronous.
+pub async fn run(
+    state: &mut State,
+    mut events_socket: Socket,
+    mut requests_socket: Socket,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    let mut commands = spawn_control_listener(state);
+    let mut dump = event_loop::open_dump(state)?;
+
+    for session in 0.. {
+        let end = run_session(
+            state,
+            events_socket,
+            requests_socket,
+            &mut commands,
+            &mut dump,
+            deadline,
+            session == 0,
+        )
+        .await;
+        if !event_loop::session_ended(end)? {
+            return Ok(());
+        }
+
+        let mut reconnect = Reconnect::new(state.options());
+        (events_socket, requests_socket) = loop {
+            time::sleep(reconnect.delay()).await;
+
+            if let Some(sockets) = reconnect.attempt() {
+                break sockets;
+            }
+        };
+    }
+
+    Ok(())
+}
+
+/// Listens on the control socket, forwarding the commands from its threads to the runtime.
+fn spawn_control_listener(state: &State) -> UnboundedReceiver<ControlRequest> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    if let Some(path) = state
+        .options()
+        .control_socket
+        .clone()
+        .or_else(control::default_path)
+    {
+        let (std_sender, std_receiver) = std_mpsc::channel();
+
+        match control::spawn_listener(&path, std_sender) {
+            Ok(()) => {
+                thread::spawn(move || {
+                    while let Ok(request) = std_receiver.recv() {
+                        if sender.send(request).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+            Err(err) => warn!("Not listening for commands: {err:#}"),
+        }
+    }
+
+    receiver
+}
+
+/// Streams events from niri into `state` until the connection is lost or a limit is reached.
+///
+/// Events sent while piri was disconnected are not replayed. Instead niri starts every event
+/// stream with a snapshot of all open windows, which `state` reconciles with what it was tracking
+/// before the connection dropped.
+async fn run_session(
+    state: &mut State,
+    mut events_socket: Socket,
+    mut requests_socket: Socket,
+    commands: &mut UnboundedReceiver<ControlRequest>,
+    dump: &mut Option<EventDump>,
+    deadline: Option<Instant>,
+    first: bool,
+) -> Result<SessionEnd> {
+    if let Some(end) = event_loop::start_event_stream(&mut events_socket)? {
+        return Ok(end);
+    }
+
+    let mut script = None;
+    let requests = event_loop::request_sink(state, &mut requests_socket, &mut script);
+    event_loop::scan(state, requests, first)?;
+
+    let mut events = events_socket.into_async_events()?;
+
+    log_connect_step(first, "Starting read of events");
+
+    let mut timers = SessionTimers::new(state, deadline);
+
+    loop {
+        if event_loop::actions_limit_reached(state) {
+            return Ok(SessionEnd::LimitReached);
+        }
+
+        tokio::select! {
+            event = events.next() => {
+                let Ok(event) = event else {
+                    return Ok(SessionEnd::Disconnected);
+                };
+
+                timers.handle_event(state, event, dump, requests)?;
+            }
+            Some(request) = commands.recv() => {
+                let _ = request.reply.send(state.control(request.command, requests));
+            }
+            () = sleep_until(timers.next_wake(state)) => (),
+        }
+
+        if let Some(end) = timers.tick(state, requests)? {
+            return Ok(end);
+        }
+    }
+}
+
+/// Sleeps until `instant`, or forever if there is none.
+async fn sleep_until(instant: Option<Instant>) {
+    match instant {
+        Some(instant) => time::sleep_until(instant.into()).await,
+        None => future::pending().await,
+    }
+}
@@ -0,0 +1,851 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use sap::{Argument, Parser};
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::ipc::SocketType;
+
+const VERSION_TEXT: &str = concat!(
+    "piri ",
+    env!("CARGO_PKG_VERSION"),
+    "\nniri-ipc ",
+    env!("PIRI_NIRI_IPC_VERSION"),
+    "\n"
+);
+
+const HELP_TEXT: &str = "piri - Make Firefox Picture-in-Picture windows persist across workspaces
+
+USAGE:
+    piri [OPTIONS]
+
+OPTIONS:
+        --activation-burst-ms <MS>
+                               Handle the workspace switches on several monitors within this long
+                               of each other together, see --burst-target [default: 0]
+        --adaptive-logging     Log at debug level for a while after repeated warnings, like
+                               failed moves or reconnects
+        --burst-target <TARGET>
+                               Where a burst of switches sends followed windows: the newly
+                               active workspace on their own monitor, or the focused one
+                               [default: window-monitor]
+                               Possible values: window-monitor, focused-monitor
+        --color-eventlog       Print the events like --verbose-events, colored by type:
+                               workspaces in blue, opened windows in green, closed ones in red
+    -c, --config <FILE>        Path to the config file, read as JSON if it ends in .json
+                               [default: $XDG_CONFIG_HOME/piri/config.toml]
+        --confirm-switch       Only move windows once a workspace switch has settled
+        --control-socket <PATH>
+                               Where to listen for commands [default: $XDG_RUNTIME_DIR/piri.sock]
+        --detect-browser       Print config rules for the browser Picture-in-Picture windows
+                               currently open and exit
+        --dump-events <FILE>   Append every event received from niri to FILE as a JSON line, for
+                               --replay. FILE is moved to FILE.1 once it grows past 16 MiB
+        --dump-state           Print the internal state of the running piri as JSON, for bug
+                               reports, and exit
+        --emit-script          Print the actions as niri msg command lines instead of performing
+                               them
+        --exact-app-id <APP_ID>
+                               Follow windows with exactly this app id instead of using the
+                               rules, compared without regexes
+        --exact-title <TITLE>  Follow windows with exactly this title instead of using the rules,
+                               compared without regexes. Both must match when combined with
+                               --exact-app-id
+        --exclude-title <REGEX>
+                               Never match windows whose title matches this regex, can be
+                               repeated and adds to exclude_titles in the config
+        --exit-after-actions <N>
+                               Exit after sending this many actions to niri
+        --exit-if-no-niri      Exit successfully if niri can't be reached at startup
+        --explain              Log why each opened or changed window did or didn't match
+        --focus                Let focus follow moved windows, unless a rule says otherwise
+        --follow-across-monitors
+                               Also follow workspace switches on other monitors than the window's,
+                               until the window is dragged to another monitor by hand
+        --follow-focus-monitor Move followed windows to whichever monitor gets focus, even if
+                               they were dragged to another one by hand. The most aggressive
+                               mode, the window is always where you look
+        --follow-on-scan       Move the followed windows found when connecting to the focused
+                               workspace right away, instead of on the next workspace switch
+        --follow-special       Also follow into the workspaces listed in special_workspaces
+        --follow-urgent        Move a followed window to the focused workspace as soon as it
+                               becomes urgent, even if following is paused or suspended
+        --idle-check-ms <MS>   Check that niri still answers after this long without events,
+                               reconnecting if it doesn't
+        --initial-workspace <WORKSPACE>
+                               Move followed windows to this workspace id or name once,
+                               when they are first detected
+        --install-service      Write a systemd user unit starting piri with the graphical
+                               session and exit, without enabling it
+        --landing-position <POSITION>
+                               Where followed windows are placed after being moved
+                               [default: natural]
+                               Possible values: natural, floating-center, last-column,
+                               first-column
+        --log-filter <FILTER>  Set per-module log levels, e.g. piri=debug,niri_ipc=warn
+                               [default: $RUST_LOG]
+    -l, --log-level <LEVEL>    Set the log level [default: info]
+                               Possible values: trace, debug, info, warn, error
+        --match-logic <LOGIC>  Whether rules need both their title and app_id to match, or
+                               either of them [default: and]
+                               Possible values: and, or
+        --match-settle-ms <MS> Only handle matching windows that stayed open this long, and
+                               only react to closes not followed by another match as quickly,
+                               ignoring quick Picture-in-Picture toggles [default: 0]
+        --move-log-level <LEVEL>
+                               Log level of the messages about moved windows [default: info]
+                               Possible values: trace, debug, info, warn, error, off
+        --no-color             Disable colored log output
+        --no-follow-special    Don't follow into the workspaces listed in special_workspaces
+                               [default]
+        --overview-behavior <BEHAVIOR>
+                               What to do when switching workspaces in the overview [default: move]
+                               Possible values: move, pause
+        --pick                 Choose a window to follow from a list instead of matching rules
+        --poll-windows-ms <MS> Also fetch the window list this often, for when events are missed
+        --print-matchers       Print the rules compiled from the config and exit
+        --print-tracked        Print the ids of the tracked windows, one per line, and exit
+        --profile <NAME>       Apply the settings of [profiles.<NAME>] in the config on top of
+                               the others
+        --reconnect-initial-ms <MS>
+                               Wait this long before reconnecting to niri the first time
+                               [default: 500]
+        --reconnect-max-ms <MS>
+                               Never wait longer than this between reconnection attempts
+                               [default: 30000]
+        --reconnect-multiplier <FACTOR>
+                               Multiply the wait by this after every failed attempt [default: 2]
+        --relative-offset <N>  Follow to the workspace N positions after the focused one on the
+                               same monitor, or before it if N is negative [default: 0]
+        --relative-wrap        Wrap around past the first and last workspaces with
+                               --relative-offset instead of stopping at them
+        --replay <FILE>        Handle the events recorded with --dump-events or --verbose-events
+                               and print the requests that would be sent instead of connecting
+                               to niri
+        --require-match        Exit with an error if no open window matches at startup
+        --run-for <SECONDS>    Exit after running for this long
+        --socket-type <TYPE>   How to connect to the address in $NIRI_SOCKET [default: auto]
+                               Possible values: auto, abstract, path
+        --uninstall-service    Remove the systemd user unit written by --install-service and exit
+        --verbose-events       Print every event received from niri as a JSON line on stderr
+        --waybar               Print the tracked windows as JSON for a waybar custom module,
+                               logging to stderr instead of stdout
+    -h, --help                 Print this help message
+    -v, --version              Print version information
+
+SIGNALS:
+    SIGUSR2                    Pause or resume moving windows
+
+COMMANDS (one per line on the control socket, answered with OK <json> or ERR <json>):
+    version                    Report the protocol and piri versions
+    status                     Describe the followed windows
+    tracked                    List the ids of the followed windows
+    follow <ID> on|off         Resume or suspend following a window, which stays tracked
+    track <ID>                 Follow a window whatever the rules say
+    untrack <ID>               Stop following a window, the rules don't match it again
+    pause                      Stop moving windows, like SIGUSR2
+    resume                     Move windows again
+    reload                     Read the config again
+    rescan                     Match the open windows against the rules again
+    dump-state                 Describe everything piri keeps track of, for bug reports
+    explain <ID>               Check a window against the rules and tell why it matches or not
+";
+
+/// Command line options.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub level_filter: LevelFilter,
+    pub log_filter: Option<String>,
+    /// Log at debug level for a while after repeated warnings.
+    pub adaptive_logging: bool,
+    /// Level of the messages logged when a window is moved, `OFF` to silence them.
+    pub move_log_level: LevelFilter,
+    pub config_path: Option<PathBuf>,
+    /// Config profile overriding the base settings.
+    pub profile: Option<String>,
+    pub color: bool,
+    /// Wait for a workspace switch to be followed by another event before moving windows.
+    pub confirm_switch: bool,
+    /// Print suggested rules for the open browser Picture-in-Picture windows and exit.
+    pub detect_browser: bool,
+    /// Write the systemd user unit and exit.
+    pub install_service: bool,
+    /// Remove the systemd user unit and exit.
+    pub uninstall_service: bool,
+    /// Print actions as `niri msg action` command lines instead of sending them.
+    pub emit_script: bool,
+    /// Ask which window to follow at startup instead of matching windows against the rules.
+    pub pick: bool,
+    pub overview_behavior: OverviewBehavior,
+    /// Workspace switches within this long of each other are handled together.
+    pub activation_burst: Duration,
+    /// Where a burst of workspace switches sends followed windows.
+    pub burst_target: BurstTarget,
+    /// Log the match decision for every opened or changed window.
+    pub explain: bool,
+    /// Let focus follow moved windows, rules can override this.
+    pub focus: bool,
+    /// Workspace followed windows are moved to when they are first detected.
+    pub initial_workspace: Option<String>,
+    /// Where followed windows are placed after being moved.
+    pub landing_position: LandingPosition,
+    /// How the title and app id checks of a rule combine.
+    pub match_logic: MatchLogic,
+    /// Follow workspace switches on any output, not only the output of the window.
+    ///
+    /// A window moved to another output by hand is anchored there regardless.
+    pub follow_across_monitors: bool,
+    /// Follow focus to any output, ignoring the output a window was moved to by hand.
+    pub follow_focus_monitor: bool,
+    /// Move the windows found by the scan on connecting to the focused workspace.
+    pub follow_on_scan: bool,
+    /// Follow into the workspaces listed in the `special_workspaces` config.
+    pub follow_special: bool,
+    /// Move followed windows to the focused workspace when they become urgent.
+    pub follow_urgent: bool,
+    /// How long a matching window must stay open before it is handled.
+    pub match_settle: Duration,
+    /// Check that niri still answers after this long without events.
+    pub idle_check: Option<Duration>,
+    /// Fetch the window list this often on top of listening to events.
+    pub poll_windows: Option<Duration>,
+    /// Delays between attempts to connect to niri.
+    pub reconnect_backoff: Backoff,
+    /// Follow to the workspace this many positions away from the focused one.
+    pub relative_offset: i32,
+    /// Wrap `relative_offset` around the workspaces of the output instead of clamping it.
+    pub relative_wrap: bool,
+    /// Fail if no window matches when piri starts.
+    pub require_match: bool,
+    /// Exit after running for this long.
+    pub run_for: Option<Duration>,
+    /// Regexes of titles that are never matched, on top of the config ones.
+    pub exclude_titles: Vec<String>,
+    /// Title windows are followed for instead of the rules.
+    pub exact_title: Option<String>,
+    /// App id windows are followed for instead of the rules.
+    pub exact_app_id: Option<String>,
+    /// Exit successfully instead of failing when niri can't be reached at startup.
+    pub exit_if_no_niri: bool,
+    /// Exit after sending this many actions.
+    pub exit_after_actions: Option<usize>,
+    pub socket_type: SocketType,
+    /// Where to listen for commands instead of the default location.
+    pub control_socket: Option<PathBuf>,
+    /// Print the compiled rules and exit.
+    pub print_matchers: bool,
+    /// Print the ids of the tracked windows and exit.
+    pub print_tracked: bool,
+    /// Print the state of the running piri and exit.
+    pub dump_state: bool,
+    /// File of recorded events to handle instead of connecting to niri.
+    pub replay: Option<PathBuf>,
+    /// Print every received event as a JSON line on stderr.
+    pub verbose_events: bool,
+    /// Color the events printed with `verbose_events` by type.
+    pub color_eventlog: bool,
+    /// File every received event is appended to as a JSON line.
+    pub dump_events: Option<PathBuf>,
+    /// Print a waybar module status on stdout whenever it changes.
+    pub waybar: bool,
+}
+
+/// Exponential backoff between attempts to connect to niri.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// Delay before the first attempt.
+    pub initial: Duration,
+    /// Longest delay between two attempts.
+    pub max: Duration,
+    /// Factor applied to the delay after every failed attempt.
+    pub multiplier: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay to wait after waiting `delay` failed.
+    pub fn next(&self, delay: Duration) -> Duration {
+        Duration::try_from_secs_f64(delay.as_secs_f64() * self.multiplier)
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+/// What to do with followed windows while the overview is open.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverviewBehavior {
+    /// Keep moving windows as usual.
+    #[default]
+    Move,
+    /// Don't move windows until the overview is closed, then move them to the focused workspace.
+    Pause,
+}
+
+impl FromStr for OverviewBehavior {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "move" => Ok(Self::Move),
+            "pause" => Ok(Self::Pause),
+            _ => bail!("Invalid overview behavior: {s}."),
+        }
+    }
+}
+
+/// How the title and app id checks of a rule combine.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MatchLogic {
+    /// Both must match.
+    #[default]
+    And,
+    /// Either one is enough.
+    Or,
+}
+
+impl FromStr for MatchLogic {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "and" => Ok(Self::And),
+            "or" => Ok(Self::Or),
+            _ => bail!("Invalid match logic: {s}."),
+        }
+    }
+}
+
+/// Where followed windows go when workspaces are switched on several monitors at once.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BurstTarget {
+    /// The workspace that became active on the monitor of the window, if any.
+    #[default]
+    WindowMonitor,
+    /// The focused workspace, whichever monitor it is on.
+    FocusedMonitor,
+}
+
+impl FromStr for BurstTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "window-monitor" => Ok(Self::WindowMonitor),
+            "focused-monitor" => Ok(Self::FocusedMonitor),
+            _ => bail!("Invalid burst target: {s}."),
+        }
+    }
+}
+
+/// Where a followed window is placed on the workspace it was moved to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LandingPosition {
+    /// Wherever niri puts it.
+    #[default]
+    Natural,
+    /// Floating, centered on the screen.
+    FloatingCenter,
+    /// Tiled in the last column.
+    LastColumn,
+    /// Tiled in the first column.
+    FirstColumn,
+}
+
+impl FromStr for LandingPosition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "natural" => Ok(Self::Natural),
+            "floating-center" => Ok(Self::FloatingCenter),
+            "last-column" => Ok(Self::LastColumn),
+            "first-column" => Ok(Self::FirstColumn),
+            _ => bail!("Invalid landing position: {s}."),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            level_filter: LevelFilter::INFO,
+            log_filter: None,
+            adaptive_logging: false,
+            move_log_level: LevelFilter::INFO,
+            config_path: None,
+            profile: None,
+            color: true,
+            confirm_switch: false,
+            detect_browser: false,
+            install_service: false,
+            uninstall_service: false,
+            emit_script: false,
+            pick: false,
+            overview_behavior: OverviewBehavior::default(),
+            activation_burst: Duration::ZERO,
+            burst_target: BurstTarget::default(),
+            explain: false,
+            focus: false,
+            initial_workspace: None,
+            landing_position: LandingPosition::default(),
+            match_logic: MatchLogic::default(),
+            follow_across_monitors: false,
+            follow_focus_monitor: false,
+            follow_on_scan: false,
+            follow_special: false,
+            follow_urgent: false,
+            match_settle: Duration::ZERO,
+            poll_windows: None,
+            idle_check: None,
+            reconnect_backoff: Backoff::default(),
+            relative_offset: 0,
+            relative_wrap: false,
+            require_match: false,
+            run_for: None,
+            exit_after_actions: None,
+            exclude_titles: Vec::new(),
+            exact_title: None,
+            exact_app_id: None,
+            exit_if_no_niri: false,
+            socket_type: SocketType::default(),
+            control_socket: None,
+            print_matchers: false,
+            print_tracked: false,
+            dump_state: false,
+            replay: None,
+            verbose_events: false,
+            color_eventlog: false,
+            dump_events: None,
+            waybar: false,
+        }
+    }
+}
+
+impl Options {
+    /// Parses the command line arguments.
+    ///
+    /// Returns `None` if piri should exit right away, e.g. after printing the help message.
+    pub fn parse() -> Result<Option<Self>> {
+        Self::parse_from(env::args())
+    }
+
+    /// Parses `args`, the first of them being the program name.
+    fn parse_from(args: impl Iterator<Item = String>) -> Result<Option<Self>> {
+        let mut parser = Parser::from_arbitrary(attach_values(args))?;
+        let mut options = Self::default();
+
+        while let Some(arg) = parser.forward()? {
+            match arg {
+                Argument::Long("log-level") => {
+                    options.level_filter = parse_level(&value(&mut parser, "log-level")?)?;
+                }
+                Argument::Long("match-settle-ms") => {
+                    options.match_settle = Duration::from_millis(
+                        value(&mut parser, "match-settle-ms")?
+                            .parse()
+                            .context("Invalid value for match-settle-ms")?,
+                    );
+                }
+                Argument::Long("move-log-level") => {
+                    let level = value(&mut parser, "move-log-level")?;
+                    options.move_log_level = match level.as_str() {
+                        "off" => LevelFilter::OFF,
+                        level => parse_level(level)?,
+                    };
+                }
+                Argument::Long("adaptive-logging") => options.adaptive_logging = true,
+                Argument::Long("log-filter") => {
+                    options.log_filter = Some(value(&mut parser, "log-filter")?);
+                }
+                Argument::Long("config") => {
+                    options.config_path = Some(PathBuf::from(value(&mut parser, "config")?));
+                }
+                Argument::Long("control-socket") => {
+                    options.control_socket =
+                        Some(PathBuf::from(value(&mut parser, "control-socket")?));
+                }
+                Argument::Long("profile") => {
+                    options.profile = Some(value(&mut parser, "profile")?);
+                }
+                Argument::Long("confirm-switch") => options.confirm_switch = true,
+                Argument::Long("detect-browser") => options.detect_browser = true,
+                Argument::Long("install-service") => options.install_service = true,
+                Argument::Long("uninstall-service") => options.uninstall_service = true,
+                Argument::Long("emit-script") => options.emit_script = true,
+                Argument::Long("exact-title") => {
+                    options.exact_title = Some(value(&mut parser, "exact-title")?);
+                }
+                Argument::Long("exact-app-id") => {
+                    options.exact_app_id = Some(value(&mut parser, "exact-app-id")?);
+                }
+                Argument::Long("exclude-title") => {
+                    options
+                        .exclude_titles
+                        .push(value(&mut parser, "exclude-title")?);
+                }
+                Argument::Long("exit-if-no-niri") => options.exit_if_no_niri = true,
+                Argument::Long("explain") => options.explain = true,
+                Argument::Long("focus") => options.focus = true,
+                Argument::Long("idle-check-ms") => {
+                    let millis = value(&mut parser, "idle-check-ms")?
+                        .parse()
+                        .context("Invalid value for idle-check-ms")?;
+                    if millis == 0 {
+                        bail!("idle-check-ms must be greater than 0");
+                    }
+                    options.idle_check = Some(Duration::from_millis(millis));
+                }
+                Argument::Long("initial-workspace") => {
+                    let workspace = value(&mut parser, "initial-workspace")?;
+                    if workspace.is_empty() {
+                        bail!("initial-workspace must not be empty");
+                    }
+                    options.initial_workspace = Some(workspace);
+                }
+                Argument::Long("landing-position") => {
+                    options.landing_position = value(&mut parser, "landing-position")?.parse()?;
+                }
+                Argument::Long("match-logic") => {
+                    options.match_logic = value(&mut parser, "match-logic")?.parse()?;
+                }
+                Argument::Long("follow-across-monitors") => options.follow_across_monitors = true,
+                Argument::Long("follow-focus-monitor") => options.follow_focus_monitor = true,
+                Argument::Long("follow-on-scan") => options.follow_on_scan = true,
+                Argument::Long("follow-special") => options.follow_special = true,
+                Argument::Long("no-follow-special") => options.follow_special = false,
+                Argument::Long("follow-urgent") => options.follow_urgent = true,
+                Argument::Long("no-color") => options.color = false,
+                Argument::Long("pick") => options.pick = true,
+                Argument::Long("poll-windows-ms") => {
+                    let millis = value(&mut parser, "poll-windows-ms")?
+                        .parse()
+                        .context("Invalid value for poll-windows-ms")?;
+                    if millis == 0 {
+                        bail!("poll-windows-ms must be greater than 0");
+                    }
+                    options.poll_windows = Some(Duration::from_millis(millis));
+                }
+                Argument::Long("print-matchers") => options.print_matchers = true,
+                Argument::Long("print-tracked") => options.print_tracked = true,
+                Argument::Long("dump-state") => options.dump_state = true,
+                Argument::Long("reconnect-initial-ms") => {
+                    options.reconnect_backoff.initial = Duration::from_millis(
+                        value(&mut parser, "reconnect-initial-ms")?
+                            .parse()
+                            .context("Invalid value for reconnect-initial-ms")?,
+                    );
+                }
+                Argument::Long("reconnect-max-ms") => {
+                    options.reconnect_backoff.max = Duration::from_millis(
+                        value(&mut parser, "reconnect-max-ms")?
+                            .parse()
+                            .context("Invalid value for reconnect-max-ms")?,
+                    );
+                }
+                Argument::Long("reconnect-multiplier") => {
+                    options.reconnect_backoff.multiplier =
+                        value(&mut parser, "reconnect-multiplier")?
+                            .parse()
+                            .context("Invalid value for reconnect-multiplier")?;
+                }
+                Argument::Long("relative-offset") => {
+                    options.relative_offset = value(&mut parser, "relative-offset")?
+                        .parse()
+                        .context("Invalid value for relative-offset")?;
+                }
+                Argument::Long("relative-wrap") => options.relative_wrap = true,
+                Argument::Long("require-match") => options.require_match = true,
+                Argument::Long("replay") => {
+                    options.replay = Some(PathBuf::from(value(&mut parser, "replay")?));
+                }
+                Argument::Long("verbose-events") => options.verbose_events = true,
+                Argument::Long("color-eventlog") => {
+                    options.verbose_events = true;
+                    options.color_eventlog = true;
+                }
+                Argument::Long("dump-events") => {
+                    options.dump_events = Some(PathBuf::from(value(&mut parser, "dump-events")?));
+                }
+                Argument::Long("waybar") => options.waybar = true,
+                Argument::Long("run-for") => {
+                    let seconds = value(&mut parser, "run-for")?
+                        .parse()
+                        .context("Invalid value for run-for")?;
+                    options.run_for = Some(Duration::from_secs(seconds));
+                }
+                Argument::Long("exit-after-actions") => {
+                    let actions = value(&mut parser, "exit-after-actions")?
+                        .parse()
+                        .context("Invalid value for exit-after-actions")?;
+                    options.exit_after_actions = Some(actions);
+                }
+                Argument::Long("socket-type") => {
+                    options.socket_type = value(&mut parser, "socket-type")?.parse()?;
+                }
+                Argument::Long("activation-burst-ms") => {
+                    options.activation_burst = Duration::from_millis(
+                        value(&mut parser, "activation-burst-ms")?
+                            .parse()
+                            .context("Invalid value for activation-burst-ms")?,
+                    );
+                }
+                Argument::Long("burst-target") => {
+                    options.burst_target = value(&mut parser, "burst-target")?.parse()?;
+                }
+                Argument::Long("overview-behavior") => {
+                    options.overview_behavior = value(&mut parser, "overview-behavior")?.parse()?;
+                }
+                Argument::Short('h') | Argument::Long("help") => {
+                    print!("{HELP_TEXT}");
+                    return Ok(None);
+                }
+                Argument::Short('v') | Argument::Long("version") => {
+                    print!("{VERSION_TEXT}");
+                    return Ok(None);
+                }
+                arg => return Err(arg.into_error(None).into()),
+            }
+        }
+
+        let backoff = options.reconnect_backoff;
+        if backoff.initial.is_zero() {
+            bail!("reconnect-initial-ms must be greater than 0");
+        }
+        if backoff.max < backoff.initial {
+            bail!("reconnect-max-ms must not be less than reconnect-initial-ms");
+        }
+        if !backoff.multiplier.is_finite() || backoff.multiplier <= 1.0 {
+            bail!("reconnect-multiplier must be a number greater than 1");
+        }
+
+        // Bursts are already handled once they settle
+        if options.confirm_switch && !options.activation_burst.is_zero() {
+            bail!("confirm-switch can't be combined with activation-burst-ms");
+        }
+
+        Ok(Some(options))
+    }
+}
+
+/// Short options taking a value, along with their long form.
+const SHORT_VALUES: [(char, &str); 2] = [('c', "config"), ('l', "log-level")];
+
+/// Long options taking a value.
+const LONG_VALUES: [&str; 27] = [
+    "activation-burst-ms",
+    "burst-target",
+    "config",
+    "control-socket",
+    "dump-events",
+    "exact-app-id",
+    "exact-title",
+    "exclude-title",
+    "exit-after-actions",
+    "idle-check-ms",
+    "initial-workspace",
+    "landing-position",
+    "log-filter",
+    "log-level",
+    "match-logic",
+    "match-settle-ms",
+    "move-log-level",
+    "overview-behavior",
+    "poll-windows-ms",
+    "profile",
+    "reconnect-initial-ms",
+    "reconnect-max-ms",
+    "reconnect-multiplier",
+    "relative-offset",
+    "replay",
+    "run-for",
+    "socket-type",
+];
+
+/// Rewrites `-l trace`, `-ltrace`, `-l=trace` and `--log-level trace` as `--log-level=trace`, and
+/// likewise for the other options taking a value, since the parser only reads values attached
+/// with `=`.
+fn attach_values(mut args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut expanded = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            expanded.push(arg);
+            expanded.extend(args);
+            break;
+        }
+
+        if let Some(long) = arg.strip_prefix("--")
+            && LONG_VALUES.contains(&long)
+        {
+            match args.next() {
+                Some(value) => expanded.push(format!("--{long}={value}")),
+                None => expanded.push(arg),
+            }
+            continue;
+        }
+
+        let mut chars = arg.chars();
+        let long = match (chars.next(), chars.next()) {
+            (Some('-'), Some(short)) => SHORT_VALUES
+                .iter()
+                .find(|&&(c, _)| c == short)
+                .map(|&(_, long)| long),
+            _ => None,
+        };
+
+        match long {
+            Some(long) => {
+                let attached = chars.as_str();
+                let value = match attached.strip_prefix('=') {
+                    Some(value) => Some(value.to_owned()),
+                    None if !attached.is_empty() => Some(attached.to_owned()),
+                    None => args.next(),
+                };
+
+                match value {
+                    Some(value) => expanded.push(format!("--{long}={value}")),
+                    None => expanded.push(format!("--{long}")),
+                }
+            }
+            None => expanded.push(arg),
+        }
+    }
+
+    expanded
+}
+
+/// Returns the value attached to the option that was just parsed.
+fn value<I, V>(parser: &mut Parser<I>, name: &str) -> Result<String>
+where
+    I: Iterator<Item = V>,
+    V: Into<String>,
+{
+    parser
+        .value()
+        .with_context(|| format!("A value must be provided for {name}"))
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    match level {
+        "trace" => Ok(LevelFilter::TRACE),
+        "debug" => Ok(LevelFilter::DEBUG),
+        "info" => Ok(LevelFilter::INFO),
+        "warn" => Ok(LevelFilter::WARN),
+        "error" => Ok(LevelFilter::ERROR),
+        _ => bail!("Invalid log level: {level}."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Options {
+        let args = ["piri"].iter().chain(args).map(|&arg| arg.to_owned());
+
+        Options::parse_from(args).unwrap().unwrap()
+    }
+
+    #[test]
+    fn log_level_separate_value() {
+        assert_eq!(parse(&["-l", "trace"]).level_filter, LevelFilter::TRACE);
+    }
+
+    #[test]
+    fn log_level_attached_value() {
+        assert_eq!(parse(&["-ltrace"]).level_filter, LevelFilter::TRACE);
+    }
+
+    #[test]
+    fn log_level_short_equals() {
+        assert_eq!(parse(&["-l=trace"]).level_filter, LevelFilter::TRACE);
+    }
+
+    #[test]
+    fn log_level_long_equals() {
+        assert_eq!(
+            parse(&["--log-level=trace"]).level_filter,
+            LevelFilter::TRACE
+        );
+    }
+
+    #[test]
+    fn attach_values_leaves_flags_and_trailing_args() {
+        let args = ["-l", "debug", "--pick", "--", "-l", "trace"].map(str::to_owned);
+
+        assert_eq!(
+            attach_values(args.into_iter()),
+            ["--log-level=debug", "--pick", "--", "-l", "trace"]
+        );
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let backoff = Backoff::default();
+
+        assert_eq!(
+            backoff.next(Duration::from_millis(500)),
+            Duration::from_secs(1)
+        );
+        assert_eq!(backoff.next(Duration::from_secs(1)), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let backoff = Backoff::default();
+
+        assert_eq!(
+            backoff.next(Duration::from_secs(20)),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            backoff.next(Duration::from_secs(30)),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            backoff.next(Duration::MAX),
+            Duration::from_secs(30),
+            "overflowing delays are capped too"
+        );
+    }
+
+    #[test]
+    fn backoff_uses_multiplier() {
+        let backoff = Backoff {
+            multiplier: 1.5,
+            ..Backoff::default()
+        };
+
+        assert_eq!(backoff.next(Duration::from_secs(2)), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn exclude_title_repeats() {
+        let options = parse(&["--exclude-title", "one", "--exclude-title=two"]);
+
+        assert_eq!(options.exclude_titles, ["one", "two"]);
+    }
+
+    #[test]
+    fn confirm_switch_rejects_bursts() {
+        let args = ["piri", "--confirm-switch", "--activation-burst-ms=50"].map(str::to_owned);
+        let err = Options::parse_from(args.into_iter()).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "confirm-switch can't be combined with activation-burst-ms"
+        );
+    }
+}
@@ -0,0 +1,713 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use chrono::{Local, NaiveTime};
+use niri_ipc::{Action, WorkspaceReferenceArg};
+use regex::Regex;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::cli::Options;
+
+/// User configuration, read from `$XDG_CONFIG_HOME/piri/config.toml` by default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Command to run when the tracked window is closed. The window id is appended as the last
+    /// argument.
+    pub on_close_command: Option<Vec<String>>,
+    /// Command to run when windows are matched, moved or closed.
+    pub notify: Option<Notify>,
+    /// niri actions performed on a window once, when it starts being followed.
+    pub on_match_actions: Vec<MatchAction>,
+    /// Maximum width in logical pixels of a window for it to be matched.
+    pub max_width: Option<i32>,
+    /// Maximum height in logical pixels of a window for it to be matched.
+    pub max_height: Option<i32>,
+    /// How windows reporting an empty app id are matched.
+    pub empty_app_id: EmptyAppId,
+    /// How rules are evaluated against a window.
+    pub rule_evaluation: RuleEvaluation,
+    /// Browsers whose Picture-in-Picture windows are matched when no rules are configured,
+    /// defaults to Firefox only.
+    pub browsers: Option<Vec<Browser>>,
+    /// Regexes of titles that are never matched, whatever the rules say.
+    pub exclude_titles: Vec<String>,
+    /// What happens to a followed window after it is moved by something other than piri.
+    pub manual_move: ManualMove,
+    /// Names of special workspaces, like a scratchpad, that windows only follow into with
+    /// `--follow-special`.
+    pub special_workspaces: Vec<String>,
+    /// Workspace, by id or name, that windows followed onto an output land on, by output name.
+    /// Windows land on the focused workspace of outputs not listed.
+    pub landing_workspaces: BTreeMap<String, String>,
+    /// Milliseconds a followed window waits for workspace switches to settle before it is moved.
+    pub debounce_ms: u64,
+    /// Minimum milliseconds between two moves of the same followed window.
+    pub cooldown_ms: u64,
+    /// Local times of day during which followed windows are moved, always when empty.
+    pub follow_schedule: Vec<TimeRange>,
+    /// Matching rules, replacing the built-in browser rules when not empty.
+    #[serde(rename = "rule")]
+    pub rules: Vec<Rule>,
+    /// Rule applied to the windows no other rule matches, which are ignored without one.
+    pub default_rule: Option<Rule>,
+    /// Named sets of settings overriding the ones above, selected with `--profile`.
+    pub profiles: BTreeMap<String, toml::Table>,
+}
+
+/// A notification command, written as a `[notify]` table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Notify {
+    /// Command and arguments, where `{event}`, `{window_id}`, `{title}` and `{workspace}` are
+    /// replaced with the details of the event.
+    #[serde(default = "default_notify_command")]
+    pub command: Vec<String>,
+    /// Events to notify about, all of them by default.
+    #[serde(default = "default_notify_events")]
+    pub events: Vec<NotifyEvent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotifyEvent {
+    /// A window was matched by a rule.
+    Match,
+    /// A window was moved to another workspace.
+    Move,
+    /// A followed window was closed.
+    Close,
+}
+
+impl NotifyEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Match => "match",
+            Self::Move => "move",
+            Self::Close => "close",
+        }
+    }
+}
+
+/// A niri action performed on a window when it starts being followed, named like its `niri msg
+/// action` form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchAction {
+    MoveWindowToFloating,
+    MoveWindowToTiling,
+    ToggleWindowRuleOpacity,
+    CenterWindow,
+    FullscreenWindow,
+    MaximizeWindowToEdges,
+}
+
+impl MatchAction {
+    /// The action applied to the window with `id`.
+    pub fn action(self, id: u64) -> Action {
+        let id = Some(id);
+
+        match self {
+            Self::MoveWindowToFloating => Action::MoveWindowToFloating { id },
+            Self::MoveWindowToTiling => Action::MoveWindowToTiling { id },
+            Self::ToggleWindowRuleOpacity => Action::ToggleWindowRuleOpacity { id },
+            Self::CenterWindow => Action::CenterWindow { id },
+            Self::FullscreenWindow => Action::FullscreenWindow { id },
+            Self::MaximizeWindowToEdges => Action::MaximizeWindowToEdges { id },
+        }
+    }
+}
+
+fn default_notify_command() -> Vec<String> {
+    ["notify-send", "piri", "Window {window_id} {event}: {title}"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_notify_events() -> Vec<NotifyEvent> {
+    vec![NotifyEvent::Match, NotifyEvent::Move, NotifyEvent::Close]
+}
+
+/// A matching rule, written as a `[[rule]]` table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Regex the window title must match.
+    pub title: Option<String>,
+    /// Regex the window app id must match.
+    pub app_id: Option<String>,
+    #[serde(default)]
+    pub action: RuleAction,
+    /// Workspace id or name used by the `move` action.
+    pub target_workspace: Option<String>,
+    /// Group of the title regex holding the workspace used by the `move-to-capture` action,
+    /// either its index or its name. Defaults to the first group.
+    pub capture: Option<CaptureGroup>,
+    /// Ignore windows matching this rule.
+    #[serde(default)]
+    pub exclude: bool,
+    /// Whether focus follows the window when it is moved, overrides `--focus`.
+    pub focus: Option<bool>,
+    /// Only follow the window while it is on the workspace being switched away from.
+    #[serde(default)]
+    pub same_workspace_as_focus: bool,
+    /// Only match floating windows.
+    #[serde(default)]
+    pub require_floating: bool,
+    /// Only match tiled windows.
+    #[serde(default)]
+    pub require_tiled: bool,
+    /// Overrides the global `debounce_ms` for windows followed by this rule.
+    pub debounce_ms: Option<u64>,
+    /// Overrides the global `cooldown_ms` for windows followed by this rule.
+    pub cooldown_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleAction {
+    /// Keep moving the window to the focused workspace.
+    #[default]
+    Follow,
+    /// Move the window to `target_workspace` once, when it is first matched.
+    Move,
+    /// Move the window once to the workspace captured from its title by the `capture` group.
+    MoveToCapture,
+    /// Only log that the window matched.
+    Log,
+}
+
+/// A capture group of a regex.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum CaptureGroup {
+    Index(usize),
+    Name(String),
+}
+
+impl fmt::Display for CaptureGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{index}"),
+            Self::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// A range of local time of day, written as `HH:MM-HH:MM`. A range ending before it starts goes
+/// past midnight.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeRange {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeRange {
+    pub fn contains(self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for TimeRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|err| format!("invalid time {time} in {value}: {err}"))
+        };
+
+        let Some((start, end)) = value.split_once('-') else {
+            return Err(format!("expected HH:MM-HH:MM, got {value}"));
+        };
+
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+/// A browser with a built-in Picture-in-Picture rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Browser {
+    Firefox,
+    /// Chromium and browsers based on it, like Chrome and Brave.
+    Chromium,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EmptyAppId {
+    /// Treat an empty app id like a missing one, which any app id regex accepts.
+    #[default]
+    Missing,
+    /// Match an empty app id against the app id regex like any other value.
+    Strict,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ManualMove {
+    /// Keep following, the window is moved again on the next workspace switch.
+    #[default]
+    Follow,
+    /// Stop following and leave the window on the workspace it was moved to.
+    Anchor,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleEvaluation {
+    /// The first matching rule decides what happens to a window.
+    #[default]
+    First,
+    /// Every matching rule applies, unless one of them excludes the window.
+    All,
+}
+
+impl Config {
+    /// Whether `follow_schedule` lets followed windows be moved at the current local time.
+    pub fn follow_scheduled_now(&self) -> bool {
+        let now = Local::now().time();
+
+        self.follow_schedule.is_empty()
+            || self.follow_schedule.iter().any(|range| range.contains(now))
+    }
+
+    /// Loads the config selected on the command line, adding the titles excluded there.
+    pub fn from_options(options: &Options) -> Result<Self> {
+        let mut config = Self::load(options.config_path.as_deref(), options.profile.as_deref())?;
+        config
+            .exclude_titles
+            .extend(options.exclude_titles.iter().cloned());
+
+        Ok(config)
+    }
+
+    /// Loads the config from `path`, or from the default location if no path is given.
+    ///
+    /// A missing file at the default location is not an error and results in the default config.
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Self> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None if profile.is_some() => bail!("No config file to read profiles from"),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound && !explicit && profile.is_none() => {
+                return Ok(Self::default());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+
+        let format = Format::of(&path);
+        let mut config: Self = format
+            .parse(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        if let Some(name) = profile {
+            let Some(overrides) = config.profiles.remove(name) else {
+                let available: Vec<_> = config.profiles.keys().map(String::as_str).collect();
+                bail!(
+                    "Profile {name} doesn't exist in {}, available profiles: {}",
+                    path.display(),
+                    if available.is_empty() {
+                        "none".to_owned()
+                    } else {
+                        available.join(", ")
+                    }
+                );
+            };
+
+            let mut table: toml::Table = format.parse(&contents)?;
+            table.remove("profiles");
+            merge(&mut table, overrides);
+
+            config = Self::deserialize(table).with_context(|| {
+                format!("Failed to apply profile {name} from {}", path.display())
+            })?;
+        }
+
+        let problems = config.problems();
+        if !problems.is_empty() {
+            let list: Vec<_> = problems
+                .iter()
+                .enumerate()
+                .map(|(index, problem)| format!("  {}. {problem}", index + 1))
+                .collect();
+
+            bail!("Invalid config {}:\n{}", path.display(), list.join("\n"));
+        }
+
+        Ok(config)
+    }
+
+    /// Checks the values that parsed but don't make sense, returning a description of each
+    /// problem prefixed with the offending key.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self
+            .on_close_command
+            .as_ref()
+            .is_some_and(|command| command.is_empty())
+        {
+            problems.push("on_close_command: must not be empty".to_owned());
+        }
+
+        for (index, action) in self.on_match_actions.iter().enumerate() {
+            if self.on_match_actions[..index].contains(action) {
+                problems.push(format!(
+                    "on_match_actions #{}: is already listed before",
+                    index + 1
+                ));
+            }
+        }
+
+        for (index, range) in self.follow_schedule.iter().enumerate() {
+            if range.start == range.end {
+                problems.push(format!(
+                    "follow_schedule #{}: starts and ends at the same time",
+                    index + 1
+                ));
+            }
+        }
+
+        for (key, max) in [
+            ("max_width", self.max_width),
+            ("max_height", self.max_height),
+        ] {
+            if max.is_some_and(|max| max <= 0) {
+                problems.push(format!("{key}: must be greater than 0"));
+            }
+        }
+
+        for (output, workspace) in &self.landing_workspaces {
+            if workspace.is_empty() {
+                problems.push(format!("landing_workspaces {output}: must not be empty"));
+            }
+        }
+
+        for (index, title) in self.exclude_titles.iter().enumerate() {
+            if let Err(err) = Regex::new(title) {
+                problems.push(format!(
+                    "exclude_titles #{}: invalid regex: {err}",
+                    index + 1
+                ));
+            }
+        }
+
+        if self.browsers.is_some() && !self.rules.is_empty() {
+            problems.push("browsers: has no effect when rules are configured".to_owned());
+        }
+
+        if let Some(rule) = &self.default_rule {
+            for (field, set) in [
+                ("title", rule.title.is_some()),
+                ("app_id", rule.app_id.is_some()),
+                ("require_floating", rule.require_floating),
+                ("require_tiled", rule.require_tiled),
+            ] {
+                if set {
+                    problems.push(format!(
+                        "default_rule {field}: the default rule applies to any window"
+                    ));
+                }
+            }
+        }
+
+        let rules = self
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| (format!("rule #{}", index + 1), rule))
+            .chain(
+                self.default_rule
+                    .iter()
+                    .map(|rule| ("default_rule".to_owned(), rule)),
+            );
+
+        for (key, rule) in rules {
+            for (field, regex) in [("title", &rule.title), ("app_id", &rule.app_id)] {
+                if let Some(Err(err)) = regex.as_deref().map(Regex::new) {
+                    problems.push(format!("{key} {field}: invalid regex: {err}"));
+                }
+            }
+
+            match (rule.action, &rule.target_workspace) {
+                (RuleAction::Move, None) => {
+                    problems.push(format!("{key} action: move requires a target_workspace"));
+                }
+                (RuleAction::Move, Some(target)) => {
+                    if target.is_empty() {
+                        problems.push(format!("{key} target_workspace: must not be empty"));
+                    }
+                }
+                (_, Some(_)) => {
+                    problems.push(format!(
+                        "{key} target_workspace: only used by the move action"
+                    ));
+                }
+                (_, None) => (),
+            }
+
+            match (rule.action, &rule.capture) {
+                (RuleAction::MoveToCapture, capture) => {
+                    match rule.title.as_deref().map(Regex::new) {
+                        Some(Ok(regex)) => {
+                            let group = capture.clone().unwrap_or(CaptureGroup::Index(1));
+                            let exists = match group {
+                                CaptureGroup::Index(index) => index < regex.captures_len(),
+                                CaptureGroup::Name(ref name) => {
+                                    regex.capture_names().any(|n| n == Some(name))
+                                }
+                            };
+
+                            if !exists {
+                                problems.push(format!(
+                                    "{key} capture: the title regex has no group {group}"
+                                ));
+                            }
+                        }
+                        // Already reported above
+                        Some(Err(_)) => (),
+                        None => problems.push(format!(
+                            "{key} action: move-to-capture requires a title regex"
+                        )),
+                    }
+                }
+                (_, Some(_)) => problems.push(format!(
+                    "{key} capture: only used by the move-to-capture action"
+                )),
+                (_, None) => (),
+            }
+
+            if rule.require_floating && rule.require_tiled {
+                problems.push(format!(
+                    "{key} require_tiled: can't be set together with require_floating"
+                ));
+            }
+
+            if rule.same_workspace_as_focus && !matches!(rule.action, RuleAction::Follow) {
+                problems.push(format!(
+                    "{key} same_workspace_as_focus: only used by the follow action"
+                ));
+            }
+
+            for (name, value) in [
+                ("debounce_ms", rule.debounce_ms),
+                ("cooldown_ms", rule.cooldown_ms),
+            ] {
+                if value.is_some() && !matches!(rule.action, RuleAction::Follow) {
+                    problems.push(format!("{key} {name}: only used by the follow action"));
+                }
+            }
+
+            if rule.exclude && rule.focus.is_some() {
+                problems.push(format!("{key} focus: has no effect on exclude rules"));
+            }
+        }
+
+        problems
+    }
+}
+
+/// Merges `overrides` into `base`, replacing every value but tables, which are merged in turn.
+fn merge(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => {
+                merge(base, overrides);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Format of a config file, told by its extension.
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// JSON for `.json` files, TOML for anything else.
+    fn of(path: &Path) -> Self {
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            Self::Json
+        } else {
+            Self::Toml
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, contents: &str) -> Result<T> {
+        Ok(match self {
+            Self::Toml => toml::from_str(contents)?,
+            Self::Json => serde_json::from_str(contents)?,
+        })
+    }
+}
+
+/// Resolves a workspace as written in the config or on the command line against the ids of the
+/// known workspaces: a number is the workspace with that id if there is one, anything else a name.
+pub fn parse_workspace(
+    value: &str,
+    workspace_ids: impl IntoIterator<Item = u64>,
+) -> WorkspaceReferenceArg {
+    match value.parse() {
+        Ok(id) if workspace_ids.into_iter().any(|known| known == id) => {
+            WorkspaceReferenceArg::Id(id)
+        }
+        _ => WorkspaceReferenceArg::Name(value.to_owned()),
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_dir.join("piri").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+max_width = 800
+exclude_titles = ["private"]
+on_match_actions = ["move-window-to-floating"]
+follow_schedule = ["22:00-06:00"]
+landing_workspaces = { HDMI-A-1 = "media" }
+
+[notify]
+command = ["notify-send", "{event}"]
+events = ["match", "close"]
+
+[[rule]]
+title = "^Picture-in-Picture$"
+app_id = "firefox$"
+focus = true
+debounce_ms = 100
+
+[[rule]]
+app_id = "^mpv$"
+action = "move"
+target_workspace = "media"
+
+[default_rule]
+action = "log"
+
+[profiles.docked]
+max_width = 2000
+"#;
+
+    const JSON: &str = r#"{
+    "max_width": 800,
+    "exclude_titles": ["private"],
+    "on_match_actions": ["move-window-to-floating"],
+    "follow_schedule": ["22:00-06:00"],
+    "landing_workspaces": { "HDMI-A-1": "media" },
+    "notify": { "command": ["notify-send", "{event}"], "events": ["match", "close"] },
+    "rule": [
+        { "title": "^Picture-in-Picture$", "app_id": "firefox$", "focus": true, "debounce_ms": 100 },
+        { "app_id": "^mpv$", "action": "move", "target_workspace": "media" }
+    ],
+    "default_rule": { "action": "log" },
+    "profiles": { "docked": { "max_width": 2000 } }
+}"#;
+
+    /// Loads `contents` from a file with the given extension, like a config passed with `-c`.
+    fn load(extension: &str, contents: &str, profile: Option<&str>) -> Config {
+        // Tests run in parallel, each needs a file of its own
+        let name = format!(
+            "piri-test-{}-{}.{extension}",
+            std::process::id(),
+            profile.unwrap_or("base")
+        );
+        let path = env::temp_dir().join(name);
+        fs::write(&path, contents).unwrap();
+        let config = Config::load(Some(&path), profile);
+        fs::remove_file(&path).unwrap();
+
+        config.unwrap()
+    }
+
+    #[test]
+    fn json_and_toml_load_the_same_config() {
+        // Not every part of the config can be compared, like the niri actions
+        assert_eq!(
+            format!("{:?}", load("toml", TOML, None)),
+            format!("{:?}", load("json", JSON, None))
+        );
+    }
+
+    #[test]
+    fn json_and_toml_profiles_apply_the_same() {
+        let toml = load("toml", TOML, Some("docked"));
+        let json = load("json", JSON, Some("docked"));
+
+        assert_eq!(toml.max_width, Some(2000));
+        assert_eq!(format!("{toml:?}"), format!("{json:?}"));
+    }
+
+    #[test]
+    fn parse_workspace_uses_existing_ids() {
+        assert_eq!(
+            parse_workspace("3", [1, 2, 3]),
+            WorkspaceReferenceArg::Id(3)
+        );
+    }
+
+    #[test]
+    fn parse_workspace_names_unknown_ids() {
+        assert_eq!(
+            parse_workspace("4", [1, 2, 3]),
+            WorkspaceReferenceArg::Name("4".to_owned())
+        );
+        assert_eq!(
+            parse_workspace("4", []),
+            WorkspaceReferenceArg::Name("4".to_owned())
+        );
+    }
+
+    #[test]
+    fn parse_workspace_names_non_numbers() {
+        assert_eq!(
+            parse_workspace("media", [1, 2, 3]),
+            WorkspaceReferenceArg::Name("media".to_owned())
+        );
+        assert_eq!(
+            parse_workspace("-1", [1]),
+            WorkspaceReferenceArg::Name("-1".to_owned())
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+
+/// Version of the control protocol, bumped whenever a command or reply changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A command sent to the control socket, one per line.
+#[derive(Debug)]
+pub enum Command {
+    /// `version`: report the protocol and piri versions.
+    Version,
+    /// `status`: describe the tracked windows.
+    Status,
+    /// `tracked`: list the ids of the tracked windows.
+    Tracked,
+    /// `follow <id> on|off`: resume or suspend following a window while keeping it tracked.
+    Follow { window_id: u64, enabled: bool },
+    /// `track <id>`: follow a window whatever the rules say.
+    Track { window_id: u64 },
+    /// `untrack <id>`: stop following a window, it isn't matched again.
+    Untrack { window_id: u64 },
+    /// `pause`: stop moving windows, like SIGUSR2.
+    Pause,
+    /// `resume`: move windows again.
+    Resume,
+    /// `reload`: read the config again.
+    Reload,
+    /// `rescan`: match the open windows against the rules again.
+    Rescan,
+    /// `explain <id>`: check a window against the rules, like `--explain`.
+    Explain { window_id: u64 },
+    /// `dump-state`: describe the whole internal state.
+    DumpState,
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let words: Vec<_> = s.split_whitespace().collect();
+        let window_id = |window_id: &str| -> Result<u64> {
+            window_id
+                .parse()
+                .with_context(|| format!("Invalid window id: {window_id}."))
+        };
+
+        match words.as_slice() {
+            ["version"] => Ok(Self::Version),
+            ["status"] => Ok(Self::Status),
+            ["tracked"] => Ok(Self::Tracked),
+            ["follow", id, state] => {
+                let enabled = match *state {
+                    "on" => true,
+                    "off" => false,
+                    _ => bail!("Invalid follow state: {state}, expected on or off."),
+                };
+
+                Ok(Self::Follow {
+                    window_id: window_id(id)?,
+                    enabled,
+                })
+            }
+            ["track", id] => Ok(Self::Track {
+                window_id: window_id(id)?,
+            }),
+            ["untrack", id] => Ok(Self::Untrack {
+                window_id: window_id(id)?,
+            }),
+            ["pause"] => Ok(Self::Pause),
+            ["resume"] => Ok(Self::Resume),
+            ["reload"] => Ok(Self::Reload),
+            ["rescan"] => Ok(Self::Rescan),
+            ["dump-state"] => Ok(Self::DumpState),
+            ["explain", id] => Ok(Self::Explain {
+                window_id: window_id(id)?,
+            }),
+            _ => bail!("Unknown command: {s}."),
+        }
+    }
+}
+
+/// The reply to a command, written as `OK <json>` or `ERR <json>` on a single line.
+#[derive(Debug)]
+pub enum Reply {
+    Ok(Value),
+    Err(String),
+}
+
+impl Reply {
+    /// The payload of an `OK` reply line, or the message of an `ERR` one.
+    pub fn parse(line: &str) -> Result<Value> {
+        match line.split_once(' ') {
+            Some(("OK", payload)) => Ok(serde_json::from_str(payload)?),
+            Some(("ERR", payload)) => {
+                let payload: Value = serde_json::from_str(payload)?;
+                bail!("{}", payload["message"].as_str().unwrap_or("Unknown error"))
+            }
+            _ => bail!("Invalid reply: {line}"),
+        }
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok(payload) => write!(f, "OK {payload}"),
+            Self::Err(message) => write!(f, "ERR {}", json!({ "message": message })),
+        }
+    }
+}
+
+/// A command waiting to be handled, along with where to send the reply.
+pub struct ControlRequest {
+    pub command: Command,
+    pub reply: Sender<Reply>,
+}
+
+/// Default location of the control socket, `$XDG_RUNTIME_DIR/piri.sock`.
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join("piri.sock"))
+}
+
+/// Sends a single command to the piri listening on `path` and returns the payload of its reply.
+pub fn send(path: &Path, command: &str) -> Result<Value> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{command}")?;
+    // The listener closes the connection once it answered everything that was sent
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    Reply::parse(reply.trim_end())
+}
+
+/// Listens for commands on the socket at `path`, handing each one to `sender`.
+pub fn spawn_listener<T>(path: &Path, sender: Sender<T>) -> Result<()>
+where
+    T: From<ControlRequest> + Send + 'static,
+{
+    // A socket left behind by a piri that didn't exit cleanly refuses connections
+    if UnixStream::connect(path).is_ok() {
+        bail!("{} is already used by another piri", path.display());
+    }
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            return Err(err).with_context(|| format!("Failed to remove {}", path.display()));
+        }
+        _ => (),
+    }
+
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Failed to bind {}", path.display()))?;
+    debug!("Listening for commands on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = sender.clone();
+
+                    thread::spawn(move || {
+                        if let Err(err) = serve(stream, &sender) {
+                            debug!("Control connection closed: {err}");
+                        }
+                    });
+                }
+                Err(err) => warn!("Failed to accept a control connection: {err}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Answers the commands sent over a single connection until it is closed.
+fn serve<T: From<ControlRequest>>(stream: UnixStream, sender: &Sender<T>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let reply = match line.parse() {
+            Ok(command) => {
+                let (reply, receiver) = mpsc::channel();
+
+                if sender
+                    .send(ControlRequest { command, reply }.into())
+                    .is_err()
+                {
+                    return Ok(());
+                }
+
+                receiver
+                    .recv()
+                    .unwrap_or_else(|_| Reply::Err("piri is exiting".to_owned()))
+            }
+            Err(err) => Reply::Err(err.to_string()),
+        };
+
+        writeln!(writer, "{reply}")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_command() {
+        let parse = |line: &str| format!("{:?}", line.parse::<Command>().unwrap());
+
+        assert_eq!(parse("version"), "Version");
+        assert_eq!(parse("status"), "Status");
+        assert_eq!(parse("tracked"), "Tracked");
+        assert_eq!(
+            parse("follow 4 off"),
+            "Follow { window_id: 4, enabled: false }"
+        );
+        assert_eq!(
+            parse("follow 4 on"),
+            "Follow { window_id: 4, enabled: true }"
+        );
+        assert_eq!(parse("track 4"), "Track { window_id: 4 }");
+        assert_eq!(parse("  untrack   4 "), "Untrack { window_id: 4 }");
+        assert_eq!(parse("pause"), "Pause");
+        assert_eq!(parse("resume"), "Resume");
+        assert_eq!(parse("reload"), "Reload");
+        assert_eq!(parse("rescan"), "Rescan");
+        assert_eq!(parse("explain 4"), "Explain { window_id: 4 }");
+        assert_eq!(parse("dump-state"), "DumpState");
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        for line in [
+            "",
+            "jump",
+            "track",
+            "track four",
+            "follow 4",
+            "follow 4 maybe",
+            "pause now",
+        ] {
+            assert!(line.parse::<Command>().is_err(), "{line:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn replies_round_trip() {
+        let ok = Reply::Ok(json!({ "matched": 2 })).to_string();
+        assert_eq!(ok, r#"OK {"matched":2}"#);
+        assert_eq!(Reply::parse(&ok).unwrap(), json!({ "matched": 2 }));
+
+        let err = Reply::Err("window#4 is not followed".to_owned()).to_string();
+        assert_eq!(err, r#"ERR {"message":"window#4 is not followed"}"#);
+        assert_eq!(
+            Reply::parse(&err).unwrap_err().to_string(),
+            "window#4 is not followed"
+        );
+
+        assert!(Reply::parse("maybe").is_err());
+    }
+
+    #[test]
+    fn answers_concurrent_clients() {
+        let path = env::temp_dir().join(format!("piri-test-{}.sock", std::process::id()));
+        let (sender, receiver) = mpsc::channel::<ControlRequest>();
+        spawn_listener(&path, sender).unwrap();
+
+        // Stands in for the event loop, echoing what was asked
+        thread::spawn(move || {
+            while let Ok(request) = receiver.recv() {
+                let reply = match request.command {
+                    Command::Track { window_id } => Reply::Ok(json!(window_id)),
+                    command => Reply::Err(format!("{command:?}")),
+                };
+                let _ = request.reply.send(reply);
+            }
+        });
+
+        let clients: Vec<_> = (0..4)
+            .map(|id| {
+                let path = path.clone();
+                thread::spawn(move || send(&path, &format!("track {id}")).unwrap())
+            })
+            .collect();
+        for (id, client) in clients.into_iter().enumerate() {
+            assert_eq!(client.join().unwrap(), json!(id));
+        }
+
+        assert_eq!(send(&path, "pause").unwrap_err().to_string(), "Pause");
+        assert_eq!(
+            send(&path, "jump").unwrap_err().to_string(),
+            "Unknown command: jump."
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
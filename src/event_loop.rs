@@ -0,0 +1,458 @@
+//! The event loop, reading events on a thread and waiting for them on a channel, and the session
+//! handling it shares with the async event loop.
+
+#[cfg(not(feature = "async"))]
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+#[cfg(not(feature = "async"))]
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use niri_ipc::{Event, Request, Response};
+use tracing::{debug, info, warn};
+
+use crate::cli::{Backoff, Options};
+#[cfg(not(feature = "async"))]
+use crate::control::{self, ControlRequest};
+use crate::ipc::{EventDump, RequestSink, Script, Socket, SocketType};
+use crate::state::State;
+use crate::{WAYBAR_INTERVAL, connect, log_connect_step, niri_answers, print_event};
+
+/// Handles events from niri until a limit is reached, reconnecting whenever the connection drops.
+#[cfg(not(feature = "async"))]
+pub fn run(
+    state: &mut State,
+    mut events_socket: Socket,
+    mut requests_socket: Socket,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+
+    if let Some(path) = state
+        .options()
+        .control_socket
+        .clone()
+        .or_else(control::default_path)
+        && let Err(err) = control::spawn_listener(&path, sender.clone())
+    {
+        warn!("Not listening for commands: {err:#}");
+    }
+
+    let mut dump = open_dump(state)?;
+
+    for session in 0.. {
+        let inputs = Inputs {
+            session,
+            sender: &sender,
+            receiver: &receiver,
+        };
+
+        let end = run_session(
+            state,
+            events_socket,
+            requests_socket,
+            inputs,
+            &mut dump,
+            deadline,
+        );
+        if !session_ended(end)? {
+            return Ok(());
+        }
+
+        let mut reconnect = Reconnect::new(state.options());
+        (events_socket, requests_socket) = loop {
+            thread::sleep(reconnect.delay());
+
+            if let Some(sockets) = reconnect.attempt() {
+                break sockets;
+            }
+        };
+    }
+
+    Ok(())
+}
+
+/// Something for the main loop to handle.
+#[cfg(not(feature = "async"))]
+enum Input {
+    /// An event from niri, sent during the given session.
+    Event(u64, Event),
+    /// The event stream of the given session ended.
+    Disconnected(u64),
+    /// A command from the control socket.
+    Control(ControlRequest),
+}
+
+#[cfg(not(feature = "async"))]
+impl From<ControlRequest> for Input {
+    fn from(request: ControlRequest) -> Self {
+        Self::Control(request)
+    }
+}
+
+/// The channel feeding the main loop, and the session the inputs are expected from.
+///
+/// The reader thread of a previous session can still deliver events from its stream after the
+/// session ended, those are ignored.
+#[cfg(not(feature = "async"))]
+struct Inputs<'a> {
+    session: u64,
+    sender: &'a Sender<Input>,
+    receiver: &'a Receiver<Input>,
+}
+
+/// Why a session with niri ended.
+pub enum SessionEnd {
+    /// niri didn't start the event stream, for the given reason.
+    Refused(String),
+    /// The connection to niri was lost.
+    Disconnected,
+    /// The `--run-for` or `--exit-after-actions` limit was reached.
+    LimitReached,
+}
+
+/// Streams events from niri into `state` until the connection is lost or a limit is reached.
+///
+/// Events sent while piri was disconnected are not replayed. Instead niri starts every event
+/// stream with a snapshot of all open windows, which `state` reconciles with what it was tracking
+/// before the connection dropped.
+#[cfg(not(feature = "async"))]
+fn run_session(
+    state: &mut State,
+    mut events_socket: Socket,
+    mut requests_socket: Socket,
+    inputs: Inputs,
+    dump: &mut Option<EventDump>,
+    deadline: Option<Instant>,
+) -> Result<SessionEnd> {
+    if let Some(end) = start_event_stream(&mut events_socket)? {
+        return Ok(end);
+    }
+
+    let mut script = None;
+    let requests = request_sink(state, &mut requests_socket, &mut script);
+    scan(state, requests, inputs.session == 0)?;
+
+    let mut read_event = events_socket.read_events();
+    let Inputs {
+        session,
+        sender,
+        receiver,
+    } = inputs;
+    let sender = sender.clone();
+
+    thread::spawn(move || {
+        while let Ok(event) = read_event() {
+            if sender.send(Input::Event(session, event)).is_err() {
+                return;
+            }
+        }
+
+        let _ = sender.send(Input::Disconnected(session));
+    });
+
+    log_connect_step(session == 0, "Starting read of events");
+
+    let mut timers = SessionTimers::new(state, deadline);
+
+    loop {
+        if actions_limit_reached(state) {
+            return Ok(SessionEnd::LimitReached);
+        }
+
+        let input = match timers.next_wake(state) {
+            Some(wake) => {
+                match receiver.recv_timeout(wake.saturating_duration_since(Instant::now())) {
+                    Ok(input) => Some(input),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(SessionEnd::Disconnected),
+                }
+            }
+            None => match receiver.recv() {
+                Ok(input) => Some(input),
+                Err(_) => return Ok(SessionEnd::Disconnected),
+            },
+        };
+
+        match input {
+            Some(Input::Event(from, event)) if from == session => {
+                timers.handle_event(state, event, dump, requests)?;
+            }
+            Some(Input::Disconnected(from)) if from == session => {
+                return Ok(SessionEnd::Disconnected);
+            }
+            Some(Input::Control(request)) => {
+                let _ = request.reply.send(state.control(request.command, requests));
+            }
+            Some(Input::Event(..) | Input::Disconnected(_)) | None => (),
+        }
+
+        if let Some(end) = timers.tick(state, requests)? {
+            return Ok(end);
+        }
+    }
+}
+
+/// Opens the `--dump-events` file, if there is one.
+pub fn open_dump(state: &State) -> Result<Option<EventDump>> {
+    match state.options().dump_events {
+        Some(ref path) => {
+            Ok(Some(EventDump::create(path).with_context(|| {
+                format!("Failed to open {}", path.display())
+            })?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Asks niri for the event stream, returning how the session ends if niri refuses it.
+pub fn start_event_stream(events_socket: &mut Socket) -> Result<Option<SessionEnd>> {
+    match events_socket.send(Request::EventStream)? {
+        Ok(Response::Handled) => Ok(None),
+        Ok(response) => Ok(Some(SessionEnd::Refused(format!(
+            "unexpected response {response:?}"
+        )))),
+        Err(err) => Ok(Some(SessionEnd::Refused(err))),
+    }
+}
+
+/// Where a session sends its requests, the script printed instead with `--emit-script`.
+pub fn request_sink<'a>(
+    state: &State,
+    requests_socket: &'a mut Socket,
+    script: &'a mut Option<Script<&'a mut Socket>>,
+) -> &'a mut dyn RequestSink {
+    if state.options().emit_script {
+        script.insert(Script::new(requests_socket))
+    } else {
+        requests_socket
+    }
+}
+
+/// Matches the windows already open when a session starts.
+pub fn scan(state: &mut State, requests: &mut dyn RequestSink, first: bool) -> Result<()> {
+    log_connect_step(first, "Trying to fetch existing windows...");
+    if let Ok(Response::Windows(windows)) = requests.send(Request::Windows)? {
+        let scanned = windows.len();
+        let matched = state.scan(windows, requests)?;
+        log_connect_step(
+            first,
+            &format!("Scanned {scanned} windows, {matched} matched"),
+        );
+    }
+    state.flush_actions(requests)?;
+    state.follow_after_scan(requests)?;
+
+    if state.options().waybar {
+        state.print_waybar();
+    }
+
+    Ok(())
+}
+
+/// Whether the `--exit-after-actions` limit was reached.
+pub fn actions_limit_reached(state: &State) -> bool {
+    let reached = state
+        .options()
+        .exit_after_actions
+        .is_some_and(|max| state.actions() >= max);
+    if reached {
+        info!("Sent {} actions, exiting", state.actions());
+    }
+
+    reached
+}
+
+/// What a session does besides handling events: polling, the idle check, the run time limit and
+/// the waybar ticks.
+pub struct SessionTimers {
+    deadline: Option<Instant>,
+    poll_interval: Option<Duration>,
+    next_poll: Option<Instant>,
+    idle_check: Option<Duration>,
+    next_idle_check: Option<Instant>,
+}
+
+impl SessionTimers {
+    pub fn new(state: &State, deadline: Option<Instant>) -> Self {
+        // Polling backs up the event stream on setups where it misses window changes
+        let poll_interval = state.options().poll_windows;
+        let idle_check = state.options().idle_check;
+
+        Self {
+            deadline,
+            poll_interval,
+            next_poll: poll_interval.map(|interval| Instant::now() + interval),
+            idle_check,
+            next_idle_check: idle_check.map(|idle| Instant::now() + idle),
+        }
+    }
+
+    /// When the session has something to do next without an event, if ever.
+    pub fn next_wake(&self, state: &State) -> Option<Instant> {
+        // Waybar mode wakes up regularly to notice changes made outside of events, like pausing
+        let tick = state
+            .options()
+            .waybar
+            .then(|| Instant::now() + WAYBAR_INTERVAL);
+
+        [
+            self.deadline,
+            tick,
+            self.next_poll,
+            self.next_idle_check,
+            state.next_timer(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Handles an event from niri, after printing and dumping it if asked to.
+    pub fn handle_event(
+        &mut self,
+        state: &mut State,
+        event: Event,
+        dump: &mut Option<EventDump>,
+        requests: &mut dyn RequestSink,
+    ) -> Result<()> {
+        self.next_idle_check = self.idle_check.map(|idle| Instant::now() + idle);
+
+        if state.options().verbose_events {
+            print_event(&event, state.options())?;
+        }
+        if let Some(ref mut file) = *dump
+            && let Err(err) = file.write(&event)
+        {
+            warn!("Stopped dumping events: {err}");
+            *dump = None;
+        }
+
+        state.handle_event(event, requests)
+    }
+
+    /// Does what is due after waking up, returning how the session ends if it does.
+    pub fn tick(
+        &mut self,
+        state: &mut State,
+        requests: &mut dyn RequestSink,
+    ) -> Result<Option<SessionEnd>> {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            info!("Run time limit reached, exiting");
+            return Ok(Some(SessionEnd::LimitReached));
+        }
+
+        state.run_timers(requests)?;
+
+        if let Some(poll) = self.next_poll
+            && Instant::now() >= poll
+        {
+            if let Ok(Response::Windows(windows)) = requests.send(Request::Windows)? {
+                state.reconcile(&windows, requests)?;
+            }
+
+            self.next_poll = self.poll_interval.map(|interval| Instant::now() + interval);
+        }
+
+        if let Some(check) = self.next_idle_check
+            && Instant::now() >= check
+        {
+            if !niri_answers(requests) {
+                return Ok(Some(SessionEnd::Disconnected));
+            }
+
+            self.next_idle_check = self.idle_check.map(|idle| Instant::now() + idle);
+        }
+
+        if state.options().waybar {
+            state.print_waybar();
+        }
+
+        Ok(None)
+    }
+}
+
+/// Logs how a session ended, returning whether to reconnect.
+pub fn session_ended(end: Result<SessionEnd>) -> Result<bool> {
+    match end {
+        Ok(SessionEnd::Refused(reason)) => bail!("niri didn't start the event stream: {reason}"),
+        Ok(SessionEnd::LimitReached) => Ok(false),
+        Ok(SessionEnd::Disconnected) => {
+            warn!("Lost connection to niri");
+            Ok(true)
+        }
+        Err(err) => {
+            warn!("Lost connection to niri: {err}");
+            Ok(true)
+        }
+    }
+}
+
+/// Connects the event and request sockets again, waiting longer after every failed attempt.
+pub struct Reconnect {
+    socket_type: SocketType,
+    backoff: Backoff,
+    delay: Duration,
+}
+
+impl Reconnect {
+    pub fn new(options: &Options) -> Self {
+        let backoff = options.reconnect_backoff;
+
+        Self {
+            socket_type: options.socket_type,
+            backoff,
+            delay: backoff.initial,
+        }
+    }
+
+    /// How long to wait before the next attempt.
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Tries to connect once, backing off further if it fails.
+    pub fn attempt(&mut self) -> Option<(Socket, Socket)> {
+        match connect(self.socket_type) {
+            Ok(sockets) => {
+                info!("Reconnected to niri");
+                Some(sockets)
+            }
+            Err(err) => {
+                self.back_off();
+                debug!(
+                    "Failed to reconnect to niri: {err}. Retrying in {:?}",
+                    self.delay
+                );
+                None
+            }
+        }
+    }
+
+    fn back_off(&mut self) {
+        self.delay = self.backoff.next(self.delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnecting_again_starts_from_the_initial_delay() {
+        let options = Options::default();
+        let mut reconnect = Reconnect::new(&options);
+        for _ in 0..10 {
+            reconnect.back_off();
+        }
+        assert_eq!(reconnect.delay(), options.reconnect_backoff.max);
+
+        assert_eq!(
+            Reconnect::new(&options).delay(),
+            options.reconnect_backoff.initial
+        );
+    }
+}
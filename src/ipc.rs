@@ -0,0 +1,403 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::bail;
+use niri_ipc::socket::SOCKET_PATH_ENV;
+use niri_ipc::{Action, Event, Reply, Request, Response, WorkspaceReferenceArg};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, Chain, Lines};
+use tracing::debug;
+
+/// How to interpret the socket address in `$NIRI_SOCKET`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SocketType {
+    /// Abstract if the address starts with `@`, a filesystem path otherwise.
+    #[default]
+    Auto,
+    /// An abstract socket name, with or without a leading `@`.
+    Abstract,
+    /// A filesystem path.
+    Path,
+}
+
+impl FromStr for SocketType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "abstract" => Ok(Self::Abstract),
+            "path" => Ok(Self::Path),
+            _ => bail!("Invalid socket type: {s}."),
+        }
+    }
+}
+
+/// Finds the address of the niri socket.
+///
+/// `$NIRI_SOCKET` is used when it is set and points to something that exists. Otherwise the
+/// socket is looked up in `$XDG_RUNTIME_DIR`, where niri creates it as
+/// `niri.$WAYLAND_DISPLAY.<pid>.sock`, first for the current Wayland display and then for any
+/// display. The most recently created socket wins if there are several.
+pub fn resolve_socket_path(socket_type: SocketType) -> io::Result<OsString> {
+    let mut tried = Vec::new();
+
+    match env::var_os(SOCKET_PATH_ENV) {
+        Some(address)
+            if socket_type == SocketType::Abstract
+                || (socket_type == SocketType::Auto && address.as_bytes().starts_with(b"@"))
+                || Path::new(&address).exists() =>
+        {
+            debug!("Using the niri socket from ${SOCKET_PATH_ENV}");
+            return Ok(address);
+        }
+        Some(address) => tried.push(format!(
+            "${SOCKET_PATH_ENV} ({} doesn't exist)",
+            Path::new(&address).display()
+        )),
+        None => tried.push(format!("${SOCKET_PATH_ENV} (not set)")),
+    }
+
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => {
+            let runtime_dir = PathBuf::from(runtime_dir);
+            let mut prefixes = Vec::new();
+
+            match env::var("WAYLAND_DISPLAY") {
+                Ok(display) => prefixes.push(format!("niri.{display}.")),
+                Err(_) => tried.push("$WAYLAND_DISPLAY (not set)".to_owned()),
+            }
+            prefixes.push("niri.".to_owned());
+
+            for prefix in prefixes {
+                let pattern = runtime_dir.join(format!("{prefix}*.sock"));
+
+                if let Some(path) = newest_socket(&runtime_dir, &prefix) {
+                    debug!("Using the niri socket matching {}", pattern.display());
+                    return Ok(path.into_os_string());
+                }
+
+                tried.push(format!("{} (no match)", pattern.display()));
+            }
+        }
+        None => tried.push("$XDG_RUNTIME_DIR (not set)".to_owned()),
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "Couldn't find the niri socket, are you running this within niri? Tried: {}",
+            tried.join(", ")
+        ),
+    ))
+}
+
+/// Returns the most recently modified `<prefix>*.sock` file in `dir`.
+fn newest_socket(dir: &Path, prefix: &str) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with(prefix) && name.ends_with(".sock")
+        })
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+}
+
+/// Where piri sends its requests to niri.
+pub trait RequestSink {
+    /// Sends a request to niri and returns the reply.
+    fn send(&mut self, request: Request) -> io::Result<Reply>;
+}
+
+/// Blocking connection to the niri socket.
+///
+/// This mirrors [`niri_ipc::socket::Socket`], which can only connect to filesystem sockets.
+///
+/// piri needs two connections: niri stops reading requests from a connection once it started an
+/// event stream on it, so requests can't be multiplexed over the event socket. Apart from the
+/// window list fetched at startup, which the snapshot at the start of the event stream
+/// supersedes, windows and workspaces are only learned about from the event socket, while the
+/// other connection sends actions. The order of the two streams never has to be reconciled.
+pub struct Socket {
+    stream: BufReader<UnixStream>,
+}
+
+impl Socket {
+    /// Connects to the niri socket found by [`resolve_socket_path`].
+    pub fn connect(socket_type: SocketType) -> io::Result<Self> {
+        Self::connect_to(&resolve_socket_path(socket_type)?, socket_type)
+    }
+
+    pub fn connect_to(address: &OsStr, socket_type: SocketType) -> io::Result<Self> {
+        let bytes = address.as_bytes();
+
+        let stream = match (socket_type, bytes.strip_prefix(b"@")) {
+            (SocketType::Auto | SocketType::Abstract, Some(name)) => {
+                UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?)?
+            }
+            (SocketType::Abstract, None) => {
+                UnixStream::connect_addr(&SocketAddr::from_abstract_name(bytes)?)?
+            }
+            (SocketType::Auto | SocketType::Path, _) => UnixStream::connect(address)?,
+        };
+
+        Ok(Self {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// Turns the socket into a function blocking until the next event arrives.
+    ///
+    /// Use this only after requesting an [`Request::EventStream`].
+    #[cfg(not(feature = "async"))]
+    pub fn read_events(self) -> impl FnMut() -> io::Result<Event> {
+        let Self { mut stream } = self;
+        let _ = stream.get_mut().shutdown(Shutdown::Write);
+
+        let mut buf = String::new();
+        move || {
+            buf.clear();
+            stream.read_line(&mut buf)?;
+            Ok(serde_json::from_str(&buf)?)
+        }
+    }
+}
+
+/// Events read from the niri socket without blocking, see [`Socket::into_async_events`].
+#[cfg(feature = "async")]
+pub struct AsyncEvents {
+    lines: Lines<tokio::io::BufReader<Chain<io::Cursor<Vec<u8>>, tokio::net::UnixStream>>>,
+}
+
+#[cfg(feature = "async")]
+impl Socket {
+    /// Turns the socket into a stream of events for the tokio runtime.
+    ///
+    /// Use this only after requesting an [`Request::EventStream`].
+    pub fn into_async_events(self) -> io::Result<AsyncEvents> {
+        // niri may already have sent events along with the reply to the request
+        let buffered = self.stream.buffer().to_vec();
+        let stream = self.stream.into_inner();
+        let _ = stream.shutdown(Shutdown::Write);
+        stream.set_nonblocking(true)?;
+
+        let reader = io::Cursor::new(buffered).chain(tokio::net::UnixStream::from_std(stream)?);
+
+        Ok(AsyncEvents {
+            lines: tokio::io::BufReader::new(reader).lines(),
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncEvents {
+    /// Waits for the next event. Cancelling the wait doesn't lose any event.
+    pub async fn next(&mut self) -> io::Result<Event> {
+        match self.lines.next_line().await? {
+            Some(line) => Ok(serde_json::from_str(&line)?),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl RequestSink for Socket {
+    fn send(&mut self, request: Request) -> io::Result<Reply> {
+        let mut buf = serde_json::to_string(&request)?;
+        buf.push('\n');
+        self.stream.get_mut().write_all(buf.as_bytes())?;
+
+        buf.clear();
+        self.stream.read_line(&mut buf)?;
+
+        Ok(serde_json::from_str(&buf)?)
+    }
+}
+
+impl<S: RequestSink + ?Sized> RequestSink for &mut S {
+    fn send(&mut self, request: Request) -> io::Result<Reply> {
+        (**self).send(request)
+    }
+}
+
+/// Prints actions as `niri msg action` command lines instead of sending them, other requests are
+/// sent as usual.
+pub struct Script<S> {
+    inner: S,
+}
+
+impl<S: RequestSink> Script<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    /// Translates an action into the arguments of its `niri msg action` form.
+    ///
+    /// Workspace ids have no command line form, `None` is returned for actions still referring to
+    /// one. [`State`](crate::state::State) replaces them with the name of the workspace when it
+    /// has one.
+    fn command(&self, action: &Action) -> io::Result<Option<Vec<String>>> {
+        let (name, fields) = match serde_json::to_value(action)? {
+            serde_json::Value::Object(variant) => variant.into_iter().next().unwrap_or_default(),
+            serde_json::Value::String(name) => (name, serde_json::Value::Null),
+            _ => return Ok(None),
+        };
+
+        let mut args = vec!["niri".to_owned(), "msg".to_owned(), "action".to_owned()];
+        args.push(kebab_case(&name));
+
+        let mut positional = Vec::new();
+        if let serde_json::Value::Object(fields) = fields {
+            for (field, value) in fields {
+                let value = match value {
+                    serde_json::Value::Null => continue,
+                    serde_json::Value::String(value) => value,
+                    serde_json::Value::Object(_) if field == "reference" => {
+                        let reference = serde_json::from_value(value)?;
+                        match workspace_arg(reference) {
+                            Some(reference) => positional.push(reference),
+                            None => return Ok(None),
+                        }
+                        continue;
+                    }
+                    value => value.to_string(),
+                };
+
+                args.push(format!("--{}", field.replace('_', "-")));
+                args.push(value);
+            }
+        }
+        args.extend(positional);
+
+        Ok(Some(args))
+    }
+}
+
+/// The command line form of a workspace reference, ids have none.
+fn workspace_arg(reference: WorkspaceReferenceArg) -> Option<String> {
+    match reference {
+        WorkspaceReferenceArg::Index(index) => Some(index.to_string()),
+        WorkspaceReferenceArg::Name(name) => Some(name),
+        WorkspaceReferenceArg::Id(_) => None,
+    }
+}
+
+impl<S: RequestSink> RequestSink for Script<S> {
+    fn send(&mut self, request: Request) -> io::Result<Reply> {
+        let Request::Action(action) = request else {
+            return self.inner.send(request);
+        };
+
+        match self.command(&action)? {
+            Some(args) => {
+                let args: Vec<_> = args.iter().map(|arg| shell_quote(arg)).collect();
+                println!("{}", args.join(" "));
+            }
+            None => println!("# No command line form for {action:?}"),
+        }
+
+        Ok(Ok(Response::Handled))
+    }
+}
+
+/// Turns `MoveWindowToWorkspace` into `move-window-to-workspace`.
+fn kebab_case(name: &str) -> String {
+    let mut kebab = String::new();
+
+    for (index, c) in name.chars().enumerate() {
+        if c.is_uppercase() && index > 0 {
+            kebab.push('-');
+        }
+        kebab.extend(c.to_lowercase());
+    }
+
+    kebab
+}
+
+/// Quotes an argument for a POSIX shell, if it needs to be.
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@".contains(c))
+    {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Size past which [`EventDump`] moves its file aside and starts a new one.
+const MAX_DUMP_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Appends events to a file as JSON lines, in the format read by `--replay`.
+///
+/// Once the file grows past [`MAX_DUMP_SIZE`] it is renamed with a `.1` suffix, replacing the
+/// previous one, so at most two files worth of events are kept.
+pub struct EventDump {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+}
+
+impl EventDump {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+        })
+    }
+
+    pub fn write(&mut self, event: &Event) -> io::Result<()> {
+        if self.size >= MAX_DUMP_SIZE {
+            self.rotate()?;
+        }
+
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+
+        *self = Self::create(&self.path)?;
+        debug!("Started a new event dump in {}", self.path.display());
+
+        Ok(())
+    }
+}
+
+/// Prints every request as a JSON line instead of sending it, replying as if niri handled it.
+pub struct Recorder;
+
+impl RequestSink for Recorder {
+    fn send(&mut self, request: Request) -> io::Result<Reply> {
+        println!("{}", serde_json::to_string(&request)?);
+
+        Ok(Ok(Response::Handled))
+    }
+}
@@ -1,152 +1,550 @@
-use std::cell::LazyCell;
-
-use anyhow::{Result, bail};
-use niri_ipc::socket::Socket;
-use niri_ipc::{Action, Event, Request, Response, Window, WorkspaceReferenceArg};
-use regex::Regex;
-use sap::{Argument, Parser};
-use tracing::{debug, info};
-use tracing_subscriber::filter::LevelFilter;
+mod adaptive_log;
+#[cfg(feature = "async")]
+mod async_event_loop;
+mod cli;
+mod config;
+mod control;
+mod event_loop;
+mod ipc;
+mod matcher;
+mod state;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-const TITLE_REGEX: LazyCell<Regex> =
-    LazyCell::new(|| Regex::new(r"^Picture-in-Picture$").expect("Invalid regex"));
+use anyhow::{Context, Result, bail};
+use niri_ipc::{Event, Request, Response, Window};
+use signal_hook::consts::SIGUSR2;
+use signal_hook::iterator::Signals;
+use tracing::{debug, info, warn};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::reload;
+use tracing_subscriber::util::SubscriberInitExt;
 
-const APP_ID_REGEX: LazyCell<Regex> =
-    LazyCell::new(|| Regex::new(r"firefox$").expect("Invalid regex"));
+use crate::adaptive_log::AdaptiveLog;
 
-const VERSION_TEXT: &str = "piri 0.1.0\n";
+use crate::cli::{Backoff, Options};
+use crate::config::Config;
+use crate::ipc::{Recorder, RequestSink, Script, Socket, SocketType};
+use crate::matcher::{Matcher, RuleAction};
+use crate::state::State;
 
-const HELP_TEXT: &str = "piri - Make Firefox Picture-in-Picture windows persist across workspaces
+const INITIAL_CONNECT_ATTEMPTS: u32 = 3;
+const WAYBAR_INTERVAL: Duration = Duration::from_secs(1);
 
-USAGE:
-    piri [OPTIONS]
+/// A window id as it is logged, `window#42`, to be easy to grep.
+struct WindowId(u64);
 
-OPTIONS:
-    -l, --log-level <LEVEL>    Set the log level [default: info]
-                               Possible values: trace, debug, info, warn, error
-    -h, --help                 Print this help message
-    -v, --version              Print version information
-";
+impl fmt::Display for WindowId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "window#{}", self.0)
+    }
+}
 
 fn main() -> Result<()> {
-    let mut parser = Parser::from_arbitrary(std::env::args())?;
-    let mut level_filter = LevelFilter::INFO;
-
-    while let Some(arg) = parser.forward()? {
-        match arg {
-            Argument::Short('l') | Argument::Long("log-level") => {
-                if let Some(level) = parser.value() {
-                    level_filter = match level.as_str() {
-                        "trace" => LevelFilter::TRACE,
-                        "debug" => LevelFilter::DEBUG,
-                        "info" => LevelFilter::INFO,
-                        "warn" => LevelFilter::WARN,
-                        "error" => LevelFilter::ERROR,
-                        _ => {
-                            bail!("Invalid log level: {level}.");
-                        }
-                    };
-
-                    continue;
-                }
+    let Some(options) = Options::parse()? else {
+        return Ok(());
+    };
 
-                bail!("A value must be provided for log-level");
-            }
-            Argument::Short('h') | Argument::Long("help") => {
-                print!("{HELP_TEXT}");
+    let level_filter = options.level_filter;
+    let directives = options
+        .log_filter
+        .clone()
+        .or_else(|| env::var("RUST_LOG").ok())
+        .unwrap_or_default();
+    let env_filter = EnvFilter::builder()
+        .with_default_directive(level_filter.into())
+        .parse(&directives)?;
+
+    // stdout belongs to waybar in waybar mode, and to the commands with --emit-script
+    let (writer, is_terminal) = if options.waybar || options.emit_script {
+        (BoxMakeWriter::new(io::stderr), io::stderr().is_terminal())
+    } else {
+        (BoxMakeWriter::new(io::stdout), io::stdout().is_terminal())
+    };
+
+    // The filter only applies to the output, the adaptive log has to see warnings below it
+    let (env_filter, reload_handle) = reload::Layer::new(env_filter);
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(options.color && is_terminal)
+        .with_filter(env_filter);
+    let adaptive_log = options.adaptive_logging.then(|| {
+        AdaptiveLog::new(reload_handle, move || {
+            EnvFilter::builder()
+                .with_default_directive(level_filter.into())
+                .parse_lossy(&directives)
+        })
+        .with_filter(LevelFilter::WARN)
+    });
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(adaptive_log)
+        .init();
+
+    if options.install_service {
+        return install_service();
+    }
+
+    if options.uninstall_service {
+        return uninstall_service();
+    }
+
+    let config = Config::from_options(&options)?;
+
+    let paused = Arc::new(AtomicBool::new(false));
+    spawn_pause_handler(paused.clone())?;
+
+    let matcher = Matcher::from_options(&config, &options)?;
+
+    if options.print_matchers {
+        print!("{matcher}");
+        return Ok(());
+    }
+
+    if options.print_tracked {
+        return print_tracked(&options, &matcher);
+    }
+
+    if options.dump_state {
+        return dump_state(&options);
+    }
+
+    if options.detect_browser {
+        return detect_browser(options.socket_type);
+    }
+
+    let mut state = State::new(options, config, matcher, paused);
+
+    if let Some(path) = state.options().replay.clone() {
+        return replay(&mut state, &path);
+    }
+
+    // niri doesn't read requests from a connection streaming events, see `Socket`
+    let socket_type = state.options().socket_type;
+    let (events_socket, mut requests_socket) = if state.options().exit_if_no_niri {
+        match initial_connect(socket_type, state.options().reconnect_backoff) {
+            Some(sockets) => sockets,
+            None => {
+                debug!("niri doesn't seem to be running, exiting");
                 return Ok(());
             }
-            Argument::Short('v') | Argument::Long("version") => {
-                print!("{VERSION_TEXT}");
-                return Ok(());
+        }
+    } else {
+        match connect(socket_type) {
+            Ok(sockets) => sockets,
+            Err(err) => match other_compositor() {
+                Some(compositor) => bail!("piri requires niri; detected: {compositor}"),
+                None => return Err(err.into()),
+            },
+        }
+    };
+
+    check_niri(&mut requests_socket)?;
+
+    if state.options().pick {
+        let window = pick_window(&mut requests_socket)?;
+        info!("Following {}", WindowId(window.id));
+        state.pick(&window);
+    }
+
+    if state.options().require_match {
+        if let Ok(Response::Windows(windows)) = requests_socket.send(Request::Windows)? {
+            if state.options().emit_script {
+                state.scan(windows, &mut Script::new(&mut requests_socket))?;
+            } else {
+                state.scan(windows, &mut requests_socket)?;
             }
-            arg => return Err(arg.into_error(None).into()),
+        }
+
+        if !state.has_matches() {
+            bail!("No open window matches the rules");
         }
     }
 
-    tracing_subscriber::fmt()
-        .with_max_level(level_filter)
-        .init();
+    let deadline = state
+        .options()
+        .run_for
+        .map(|run_for| Instant::now() + run_for);
 
-    let mut events_socket = Socket::connect()?;
-    let mut requests_socket = Socket::connect()?;
+    #[cfg(not(feature = "async"))]
+    return event_loop::run(&mut state, events_socket, requests_socket, deadline);
 
-    let mut pip_window = None;
+    #[cfg(feature = "async")]
+    return tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async_event_loop::run(
+            &mut state,
+            events_socket,
+            requests_socket,
+            deadline,
+        ));
+}
 
-    if matches!(
-        events_socket.send(Request::EventStream)?,
-        Ok(Response::Handled)
-    ) {
-        info!("Trying to fetch existing windows...");
-        if let Ok(Response::Windows(windows)) = requests_socket.send(Request::Windows)? {
-            for window in windows {
-                if window_matches(&window) {
-                    info!("Found a matching window with id {}", window.id);
-                    pip_window = Some(window.id);
-                    break;
-                }
+/// Handles the events recorded in `path`, one JSON line each, printing the requests piri would
+/// send.
+fn replay(state: &mut State, path: &Path) -> Result<()> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
 
-                debug!(
-                    "Ignoring window \"{}\"",
-                    window.title.unwrap_or(window.id.to_string())
-                )
-            }
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
         }
 
-        let mut read_event = events_socket.read_events();
+        let event: Event = match serde_json::from_str(line) {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("Skipping line {} of {}: {err}", index + 1, path.display());
+                continue;
+            }
+        };
 
-        info!("Starting read of events");
+        if state.options().emit_script {
+            state.handle_event(event, &mut Script::new(Recorder))?;
+        } else {
+            state.handle_event(event, &mut Recorder)?;
+        }
+    }
 
-        while let Ok(event) = read_event() {
-            match event {
-                Event::WorkspaceActivated { id, focused } => {
-                    if focused && let Some(window) = pip_window {
-                        info!("Workspace {} focused. Moving window {}", id, window);
+    Ok(())
+}
 
-                        let _ = requests_socket.send(Request::Action(
-                            Action::MoveWindowToWorkspace {
-                                window_id: Some(window),
-                                reference: WorkspaceReferenceArg::Id(id),
-                                focus: false,
-                            },
-                        ))?;
-                    } else {
-                        debug!("Workspace {} focused but no window was detected", id);
-                    }
-                }
-                Event::WindowOpenedOrChanged { ref window } => {
-                    if window_matches(window) && pip_window != Some(window.id) {
-                        info!("Window {} matched regexs", window.id);
-                        pip_window = Some(window.id);
-                    }
-                }
-                Event::WindowClosed { id } => {
-                    if let Some(window) = pip_window
-                        && window == id
-                    {
-                        info!("Window {} got closed", window);
-
-                        pip_window = None
-                    }
+/// Prints the ids of the windows tracked by the running piri, or by the rules if none is running.
+fn print_tracked(options: &Options, matcher: &Matcher) -> Result<()> {
+    if let Some(path) = options
+        .control_socket
+        .clone()
+        .or_else(control::default_path)
+    {
+        match control::send(&path, "tracked") {
+            Ok(ids) => {
+                for id in ids.as_array().into_iter().flatten() {
+                    println!("{id}");
                 }
-                _ => (),
+                return Ok(());
             }
+            Err(err) => debug!("No piri answered on {}: {err}", path.display()),
+        }
+    }
+
+    let mut requests_socket = Socket::connect(options.socket_type)?;
+    let Ok(Response::Windows(mut windows)) = requests_socket.send(Request::Windows)? else {
+        bail!("Failed to fetch the open windows");
+    };
+    windows.sort_unstable_by_key(|window| window.id);
+
+    for window in windows {
+        if matcher
+            .matching_rules(&window)
+            .iter()
+            .any(|rule| matches!(rule.action, RuleAction::Follow))
+        {
+            println!("{}", window.id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the state of the running piri, see [`state::StateSnapshot`].
+fn dump_state(options: &Options) -> Result<()> {
+    let Some(path) = options
+        .control_socket
+        .clone()
+        .or_else(control::default_path)
+    else {
+        bail!("No control socket, pass --control-socket");
+    };
+
+    let state = control::send(&path, "dump-state")
+        .with_context(|| format!("No piri answered on {}", path.display()))?;
+    println!("{}", serde_json::to_string_pretty(&state)?);
+
+    Ok(())
+}
+
+/// Writes the systemd user unit running the current executable, leaving enabling it to the user.
+fn install_service() -> Result<()> {
+    let path = service_path().context("Neither $XDG_CONFIG_HOME nor $HOME is set")?;
+    let executable = env::current_exe().context("Failed to find the piri executable")?;
+
+    // systemd expands specifiers starting with `%` in the command line
+    let mut exec_start = executable.to_string_lossy().replace('%', "%%");
+    if exec_start.contains(char::is_whitespace) {
+        exec_start = format!("\"{exec_start}\"");
+    }
+
+    // The unit installed by packages, running this executable instead
+    let unit = include_str!("../resources/piri.service")
+        .lines()
+        .map(|line| match line.strip_prefix("ExecStart=") {
+            Some(_) => format!("ExecStart={exec_start}\n"),
+            None => format!("{line}\n"),
+        })
+        .collect::<String>();
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    fs::write(&path, unit).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    println!("Wrote {}", path.display());
+    println!(
+        "Enable it with: systemctl --user daemon-reload && systemctl --user enable --now piri"
+    );
+
+    Ok(())
+}
+
+/// Removes the systemd user unit written by [`install_service`].
+fn uninstall_service() -> Result<()> {
+    let path = service_path().context("Neither $XDG_CONFIG_HOME nor $HOME is set")?;
+
+    match fs::remove_file(&path) {
+        Ok(()) => {
+            println!("Removed {}", path.display());
+            println!("Reload systemd with: systemctl --user daemon-reload");
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            bail!(
+                "{} doesn't exist, the service isn't installed",
+                path.display()
+            )
         }
+        Err(err) => Err(err).with_context(|| format!("Failed to remove {}", path.display())),
+    }
+}
+
+/// Where the systemd user unit of piri lives.
+fn service_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_dir.join("systemd").join("user").join("piri.service"))
+}
+
+/// Prints a `[[rule]]` for every distinct browser Picture-in-Picture window that is open.
+fn detect_browser(socket_type: SocketType) -> Result<()> {
+    let mut requests_socket = Socket::connect(socket_type)?;
+    let Ok(Response::Windows(windows)) = requests_socket.send(Request::Windows)? else {
+        bail!("Failed to fetch the open windows");
+    };
+
+    let mut rules = Vec::new();
+    for window in &windows {
+        if let (Some(title), Some(app_id)) = (&window.title, &window.app_id)
+            && looks_like_pip(window)
+            && !rules.contains(&(title, app_id))
+        {
+            rules.push((title, app_id));
+        }
+    }
+
+    if rules.is_empty() {
+        bail!("No browser Picture-in-Picture window is open, open one and try again");
+    }
+
+    println!("# Suggested by piri --detect-browser");
+    for (title, app_id) in rules {
+        println!();
+        println!("[[rule]]");
+        println!(
+            "title = {}",
+            toml::Value::from(format!("^{}$", regex::escape(title)))
+        );
+        println!(
+            "app_id = {}",
+            toml::Value::from(format!("^{}$", regex::escape(app_id)))
+        );
     }
 
     Ok(())
 }
 
-fn window_matches(window: &Window) -> bool {
-    let app_id_matches = if let Some(ref app_id) = window.app_id {
-        APP_ID_REGEX.is_match(app_id)
+/// Whether the window seems to be the Picture-in-Picture window of a browser: a browser window
+/// either titled like one, or floating and small.
+fn looks_like_pip(window: &Window) -> bool {
+    const BROWSERS: [&str; 8] = [
+        "firefox",
+        "librewolf",
+        "zen",
+        "chromium",
+        "chrome",
+        "brave",
+        "vivaldi",
+        "edge",
+    ];
+    const MAX_SIZE: (i32, i32) = (960, 720);
+
+    let app_id = window.app_id.as_deref().unwrap_or_default().to_lowercase();
+    if !BROWSERS.iter().any(|browser| app_id.contains(browser)) {
+        return false;
+    }
+
+    let title = window.title.as_deref().unwrap_or_default().to_lowercase();
+    let (width, height) = window.layout.window_size;
+
+    title.contains("picture-in-picture")
+        || title.contains("picture in picture")
+        || (window.is_floating && width <= MAX_SIZE.0 && height <= MAX_SIZE.1)
+}
+
+/// Lists the open windows and asks the user to choose one on stdin.
+fn pick_window(requests_socket: &mut Socket) -> Result<Window> {
+    let Ok(Response::Windows(windows)) = requests_socket.send(Request::Windows)? else {
+        bail!("Failed to fetch the open windows");
+    };
+
+    if windows.is_empty() {
+        bail!("There are no open windows to pick from");
+    }
+
+    for (index, window) in windows.iter().enumerate() {
+        println!(
+            "{index}: {} ({})",
+            window.title.as_deref().unwrap_or("<untitled>"),
+            window.app_id.as_deref().unwrap_or("<no app id>")
+        );
+    }
+
+    print!("Window to follow: ");
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+
+    let index: usize = line.trim().parse().context("Invalid window index")?;
+
+    windows
+        .into_iter()
+        .nth(index)
+        .with_context(|| format!("There is no window with index {index}"))
+}
+
+/// Makes sure the socket belongs to niri, which is the only compositor piri works with.
+fn check_niri(requests_socket: &mut Socket) -> Result<()> {
+    match requests_socket.send(Request::Version) {
+        Ok(Ok(Response::Version(version))) => {
+            debug!("Connected to niri {version}");
+            Ok(())
+        }
+        Ok(Ok(response)) => bail!("piri requires niri; detected: unexpected reply {response:?}"),
+        // An older niri may not know the request yet but still answers like niri does
+        Ok(Err(err)) => {
+            debug!("niri didn't report its version: {err}");
+            Ok(())
+        }
+        Err(err) => bail!(
+            "piri requires niri; detected: {}",
+            other_compositor().unwrap_or_else(|| format!("a socket not speaking niri IPC ({err})"))
+        ),
+    }
+}
+
+/// Name of the desktop reported in `$XDG_CURRENT_DESKTOP`, unless it is niri.
+fn other_compositor() -> Option<String> {
+    env::var("XDG_CURRENT_DESKTOP")
+        .ok()
+        .filter(|desktop| !desktop.is_empty() && !desktop.eq_ignore_ascii_case("niri"))
+}
+
+/// Logs a step of connecting to niri, at info level only for the first connection so reconnects
+/// don't repeat it.
+fn log_connect_step(first: bool, step: &str) {
+    if first {
+        info!("{step}");
     } else {
-        true
+        debug!("{step}");
+    }
+}
+
+/// Prints an event received from niri as a JSON line on stderr, colored by type with
+/// `--color-eventlog`.
+fn print_event(event: &Event, options: &Options) -> Result<()> {
+    let line = serde_json::to_string(event)?;
+
+    let color = match event {
+        _ if !(options.color_eventlog && options.color && io::stderr().is_terminal()) => None,
+        Event::WorkspacesChanged { .. }
+        | Event::WorkspaceUrgencyChanged { .. }
+        | Event::WorkspaceActivated { .. }
+        | Event::WorkspaceActiveWindowChanged { .. } => Some("34"),
+        Event::WindowOpenedOrChanged { .. } => Some("32"),
+        Event::WindowClosed { .. } => Some("31"),
+        _ => None,
     };
 
-    if let Some(ref title) = window.title {
-        return TITLE_REGEX.is_match(title) && app_id_matches;
+    match color {
+        Some(color) => eprintln!("\x1b[{color}m{line}\x1b[0m"),
+        None => eprintln!("{line}"),
     }
 
-    false
+    Ok(())
+}
+
+/// Whether niri still answers requests, for noticing connections that died without an error.
+fn niri_answers(requests: &mut dyn RequestSink) -> bool {
+    match requests.send(Request::Version) {
+        Ok(_) => true,
+        Err(err) => {
+            warn!("niri didn't answer after a while without events: {err}");
+            false
+        }
+    }
+}
+
+/// Connects the event and request sockets.
+fn connect(socket_type: SocketType) -> io::Result<(Socket, Socket)> {
+    Ok((Socket::connect(socket_type)?, Socket::connect(socket_type)?))
+}
+
+/// Connects the event and request sockets, giving up after a few attempts.
+fn initial_connect(socket_type: SocketType, backoff: Backoff) -> Option<(Socket, Socket)> {
+    let mut delay = backoff.initial;
+
+    for attempt in 1..=INITIAL_CONNECT_ATTEMPTS {
+        match connect(socket_type) {
+            Ok(sockets) => return Some(sockets),
+            Err(err) if attempt < INITIAL_CONNECT_ATTEMPTS => {
+                debug!("Failed to connect to niri: {err}. Retrying in {delay:?}");
+                thread::sleep(delay);
+                delay = backoff.next(delay);
+            }
+            Err(err) => debug!("Failed to connect to niri: {err}"),
+        }
+    }
+
+    None
+}
+
+/// Toggles `paused` every time SIGUSR2 is received.
+fn spawn_pause_handler(paused: Arc<AtomicBool>) -> Result<()> {
+    let mut signals = Signals::new([SIGUSR2])?;
+
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if paused.fetch_xor(true, Ordering::Relaxed) {
+                info!("Resumed moving windows");
+            } else {
+                info!("Paused, windows will not be moved until resumed");
+            }
+        }
+    });
+
+    Ok(())
 }
@@ -0,0 +1,1011 @@
+use std::fmt;
+use std::ptr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use niri_ipc::Window;
+use regex::Regex;
+use serde_json::{Value, json};
+
+use crate::WindowId;
+use crate::cli::{MatchLogic, Options};
+use crate::config::{self, Browser, CaptureGroup, Config, EmptyAppId, RuleEvaluation};
+
+const FIREFOX_TITLE: &str = r"^Picture-in-Picture$";
+const FIREFOX_APP_ID: &str = r"firefox$";
+
+const CHROMIUM_TITLE: &str = r"(?i)^picture[ -]in[ -]picture$";
+const CHROMIUM_APP_ID: &str = r"(?i)^(chromium|chromium-browser|google-chrome|brave-browser)$";
+
+/// What to do with a window matched by a rule.
+#[derive(Debug, Clone)]
+pub enum RuleAction {
+    /// Keep moving the window to the focused workspace.
+    Follow,
+    /// Move the window to a workspace once, when it is first matched.
+    Move(String),
+    /// Move the window once to the workspace captured from its title by a group.
+    MoveToCapture(CaptureGroup),
+    /// Only log that the window matched.
+    Log,
+}
+
+/// What the title or app id of a window is compared with.
+#[derive(Debug)]
+enum Pattern {
+    Regex(Regex),
+    /// The whole string, compared without a regex.
+    Exact(String),
+}
+
+impl Pattern {
+    fn is_match(&self, value: &str) -> bool {
+        match self {
+            Self::Regex(regex) => regex.is_match(value),
+            Self::Exact(exact) => exact == value,
+        }
+    }
+}
+
+/// A compiled matching rule.
+#[derive(Debug)]
+pub struct Rule {
+    title: Option<Pattern>,
+    app_id: Option<Pattern>,
+    pub action: RuleAction,
+    pub exclude: bool,
+    /// Whether the window must be floating (`true`) or tiled (`false`), `None` for either.
+    floating: Option<bool>,
+    /// Whether focus follows the window when it is moved, `None` to use the global default.
+    pub focus: Option<bool>,
+    /// Only follow the window while it is on the workspace being switched away from.
+    pub same_workspace_as_focus: bool,
+    /// How long switches must settle before the window is moved, `None` to use the global default.
+    pub debounce: Option<Duration>,
+    /// Minimum time between two moves of the window, `None` to use the global default.
+    pub cooldown: Option<Duration>,
+}
+
+impl Rule {
+    fn from_config(rule: &config::Rule) -> Result<Self> {
+        let title = rule
+            .title
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("Invalid title regex")?
+            .map(Pattern::Regex);
+        let app_id = rule
+            .app_id
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("Invalid app_id regex")?
+            .map(Pattern::Regex);
+
+        let action = match rule.action {
+            config::RuleAction::Follow => RuleAction::Follow,
+            config::RuleAction::Move => {
+                let target = rule.target_workspace.as_deref().unwrap_or_default();
+                RuleAction::Move(target.to_owned())
+            }
+            config::RuleAction::MoveToCapture => {
+                RuleAction::MoveToCapture(rule.capture.clone().unwrap_or(CaptureGroup::Index(1)))
+            }
+            config::RuleAction::Log => RuleAction::Log,
+        };
+
+        Ok(Self {
+            title,
+            app_id,
+            action,
+            exclude: rule.exclude,
+            floating: match (rule.require_floating, rule.require_tiled) {
+                (true, false) => Some(true),
+                (false, true) => Some(false),
+                _ => None,
+            },
+            focus: rule.focus,
+            same_workspace_as_focus: rule.same_workspace_as_focus,
+            debounce: rule.debounce_ms.map(Duration::from_millis),
+            cooldown: rule.cooldown_ms.map(Duration::from_millis),
+        })
+    }
+
+    /// The built-in rule following the Picture-in-Picture windows of a browser.
+    fn browser(browser: Browser) -> Self {
+        let (title, app_id) = match browser {
+            Browser::Firefox => (FIREFOX_TITLE, FIREFOX_APP_ID),
+            Browser::Chromium => (CHROMIUM_TITLE, CHROMIUM_APP_ID),
+        };
+
+        Self {
+            title: Some(Pattern::Regex(Regex::new(title).expect("Invalid regex"))),
+            app_id: Some(Pattern::Regex(Regex::new(app_id).expect("Invalid regex"))),
+            action: RuleAction::Follow,
+            exclude: false,
+            floating: None,
+            focus: None,
+            same_workspace_as_focus: false,
+            debounce: None,
+            cooldown: None,
+        }
+    }
+
+    /// A rule following the windows with exactly this title and app id, any if `None`.
+    fn exact(title: Option<&str>, app_id: Option<&str>) -> Self {
+        Self {
+            title: title.map(|title| Pattern::Exact(title.to_owned())),
+            app_id: app_id.map(|app_id| Pattern::Exact(app_id.to_owned())),
+            action: RuleAction::Follow,
+            exclude: false,
+            floating: None,
+            focus: None,
+            same_workspace_as_focus: false,
+            debounce: None,
+            cooldown: None,
+        }
+    }
+
+    /// Resolves the workspace captured from the window title by `group`, if the title has one.
+    pub fn captured_workspace(&self, window: &Window, group: &CaptureGroup) -> Option<String> {
+        let Some(Pattern::Regex(ref regex)) = self.title else {
+            return None;
+        };
+        let captures = regex.captures(window.title.as_deref()?)?;
+        let captured = match group {
+            CaptureGroup::Index(index) => captures.get(*index),
+            CaptureGroup::Name(name) => captures.name(name),
+        }?;
+
+        Some(captured.as_str().to_owned()).filter(|captured| !captured.is_empty())
+    }
+
+    fn check(&self, window: &Window, app_id: Option<&str>, logic: MatchLogic) -> RuleCheck {
+        // Windows without an app id are not rejected by the app id regex, but with the or logic
+        // that would let the regex accept them whatever their title
+        let app_id = self.app_id.as_ref().map(|pattern| match app_id {
+            Some(app_id) => pattern.is_match(app_id),
+            None => logic == MatchLogic::And,
+        });
+
+        let title = self.title.as_ref().map(|pattern| {
+            window
+                .title
+                .as_deref()
+                .is_some_and(|title| pattern.is_match(title))
+        });
+
+        let floating = self.floating.map(|floating| window.is_floating == floating);
+
+        RuleCheck {
+            title,
+            app_id,
+            floating,
+            logic,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn pattern(
+            f: &mut fmt::Formatter<'_>,
+            name: &str,
+            pattern: Option<&Pattern>,
+        ) -> fmt::Result {
+            let regex = match pattern {
+                Some(Pattern::Regex(regex)) => regex,
+                Some(Pattern::Exact(exact)) => return writeln!(f, "    {name}: {exact:?} [exact]"),
+                None => return writeln!(f, "    {name}: any"),
+            };
+
+            let source = regex.as_str();
+            let mut flags = Vec::new();
+
+            if source.starts_with("(?i)") {
+                flags.push("case-insensitive");
+            }
+
+            let start = source.trim_start_matches("(?i)").starts_with('^');
+            let end = source.ends_with('$');
+            flags.push(match (start, end) {
+                (true, true) => "anchored",
+                (true, false) => "anchored at start",
+                (false, true) => "anchored at end",
+                (false, false) => "unanchored",
+            });
+
+            writeln!(f, "    {name}: {source} [{}]", flags.join(", "))
+        }
+
+        if self.exclude {
+            writeln!(f, "exclude")?;
+        } else {
+            match &self.action {
+                RuleAction::Follow => writeln!(f, "follow")?,
+                RuleAction::Move(target) => writeln!(f, "move to workspace {target}")?,
+                RuleAction::MoveToCapture(group) => {
+                    writeln!(f, "move to the workspace in title group {group}")?
+                }
+                RuleAction::Log => writeln!(f, "log")?,
+            }
+        }
+
+        pattern(f, "title", self.title.as_ref())?;
+        pattern(f, "app_id", self.app_id.as_ref())?;
+
+        match self.floating {
+            Some(true) => writeln!(f, "    require_floating: true")?,
+            Some(false) => writeln!(f, "    require_tiled: true")?,
+            None => (),
+        }
+
+        if let Some(focus) = self.focus {
+            writeln!(f, "    focus: {focus}")?;
+        }
+
+        if self.same_workspace_as_focus {
+            writeln!(f, "    same_workspace_as_focus: true")?;
+        }
+
+        if let Some(debounce) = self.debounce {
+            writeln!(f, "    debounce: {debounce:?}")?;
+        }
+
+        if let Some(cooldown) = self.cooldown {
+            writeln!(f, "    cooldown: {cooldown:?}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Result of checking a single rule against a window.
+#[derive(Debug, Clone, Copy)]
+pub struct RuleCheck {
+    /// Whether the title regex matched, `None` if the rule has no title regex.
+    pub title: Option<bool>,
+    /// Whether the app id regex matched, `None` if the rule has no app id regex.
+    pub app_id: Option<bool>,
+    /// Whether the window is floating or tiled as required, `None` if the rule takes either.
+    pub floating: Option<bool>,
+    /// How the title and app id results combine.
+    pub logic: MatchLogic,
+}
+
+impl RuleCheck {
+    pub fn matched(&self) -> bool {
+        let names = match (self.logic, self.title, self.app_id) {
+            (MatchLogic::Or, Some(title), Some(app_id)) => title || app_id,
+            _ => self.title.unwrap_or(true) && self.app_id.unwrap_or(true),
+        };
+
+        names && self.floating.unwrap_or(true)
+    }
+}
+
+/// Why a window did or didn't match, see [`Matcher::explain`].
+#[derive(Debug)]
+pub struct Explanation<'a> {
+    pub window_id: u64,
+    /// Whether the window is within the size constraints.
+    pub fits: bool,
+    /// Whether the title is excluded by `exclude_titles`.
+    pub title_excluded: bool,
+    /// The result of every rule, in evaluation order.
+    pub checks: Vec<(&'a Rule, RuleCheck)>,
+    /// The rules that apply to the window.
+    pub applied: Vec<&'a Rule>,
+    /// Whether the default rule applies, since no other rule matched.
+    pub fallback: bool,
+}
+
+impl Explanation<'_> {
+    /// The explanation as a JSON object, rules are numbered from 1 like in the config.
+    pub fn json(&self) -> Value {
+        let number = |rule: &Rule| {
+            self.checks
+                .iter()
+                .position(|&(checked, _)| ptr::eq(checked, rule))
+                .map(|index| index + 1)
+        };
+
+        let rules: Vec<_> = self
+            .checks
+            .iter()
+            .enumerate()
+            .map(|(index, (rule, check))| {
+                json!({
+                    "rule": index + 1,
+                    "exclude": rule.exclude,
+                    "title": check.title,
+                    "app_id": check.app_id,
+                    "floating": check.floating,
+                    "matched": check.matched(),
+                })
+            })
+            .collect();
+        let applied: Vec<_> = self
+            .applied
+            .iter()
+            .filter_map(|rule| number(rule))
+            .collect();
+
+        json!({
+            "id": self.window_id,
+            "fits": self.fits,
+            "title_excluded": self.title_excluded,
+            "rules": rules,
+            "applied": applied,
+            "default": self.fallback,
+            "explanation": self.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Explanation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn result(check: Option<bool>) -> &'static str {
+            match check {
+                Some(true) => "match",
+                Some(false) => "no match",
+                None => "any",
+            }
+        }
+
+        write!(f, "{}: ", WindowId(self.window_id))?;
+
+        if !self.fits {
+            write!(f, "too large, ")?;
+        }
+
+        if self.title_excluded {
+            write!(f, "title excluded, ")?;
+        }
+
+        for (index, (rule, check)) in self.checks.iter().enumerate() {
+            write!(
+                f,
+                "rule #{} [title: {}, app id: {}, layout: {}{}], ",
+                index + 1,
+                result(check.title),
+                result(check.app_id),
+                result(check.floating),
+                if rule.exclude { ", exclude" } else { "" }
+            )?;
+        }
+
+        if self.fallback {
+            write!(f, "default rule, ")?;
+        }
+
+        if self.applied.is_empty() {
+            write!(f, "decision: ignored")
+        } else {
+            let actions: Vec<_> = self
+                .applied
+                .iter()
+                .map(|rule| format!("{:?}", rule.action))
+                .collect();
+
+            write!(f, "decision: {}", actions.join(", "))
+        }
+    }
+}
+
+/// Decides which rules apply to a window.
+#[derive(Debug)]
+pub struct Matcher {
+    rules: Vec<Rule>,
+    /// Applied when no rule matches.
+    fallback: Option<Rule>,
+    exclude_titles: Vec<Regex>,
+    evaluation: RuleEvaluation,
+    logic: MatchLogic,
+    empty_app_id: EmptyAppId,
+    max_width: Option<i32>,
+    max_height: Option<i32>,
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Rule evaluation: {:?}", self.evaluation)?;
+        writeln!(f, "Match logic: {:?}", self.logic)?;
+
+        if let Some(max) = self.max_width {
+            writeln!(f, "Max width: {max}")?;
+        }
+        if let Some(max) = self.max_height {
+            writeln!(f, "Max height: {max}")?;
+        }
+
+        for regex in &self.exclude_titles {
+            writeln!(f, "Excluded title: {}", regex.as_str())?;
+        }
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            write!(f, "Rule #{}: {rule}", index + 1)?;
+        }
+
+        if let Some(rule) = &self.fallback {
+            write!(f, "Default rule: {rule}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Matcher {
+    pub fn new(config: &Config) -> Result<Self> {
+        let rules = if config.rules.is_empty() {
+            config
+                .browsers
+                .as_deref()
+                .unwrap_or(&[Browser::Firefox])
+                .iter()
+                .map(|&browser| Rule::browser(browser))
+                .collect()
+        } else {
+            config
+                .rules
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| {
+                    Rule::from_config(rule).with_context(|| format!("Invalid rule #{}", index + 1))
+                })
+                .collect::<Result<_>>()?
+        };
+
+        let fallback = config
+            .default_rule
+            .as_ref()
+            .map(Rule::from_config)
+            .transpose()
+            .context("Invalid default rule")?;
+
+        let exclude_titles = config
+            .exclude_titles
+            .iter()
+            .map(|title| {
+                Regex::new(title).with_context(|| format!("Invalid exclude title regex {title}"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            rules,
+            fallback,
+            exclude_titles,
+            evaluation: config.rule_evaluation,
+            logic: MatchLogic::default(),
+            empty_app_id: config.empty_app_id,
+            max_width: config.max_width,
+            max_height: config.max_height,
+        })
+    }
+
+    /// Builds the matcher for the command line, where `--exact-title` and `--exact-app-id` replace
+    /// the rules of the config and `--match-logic` decides how their checks combine.
+    pub fn from_options(config: &Config, options: &Options) -> Result<Self> {
+        let mut matcher = Self::new(config)?;
+        matcher.logic = options.match_logic;
+
+        if options.exact_title.is_some() || options.exact_app_id.is_some() {
+            matcher.rules = vec![Rule::exact(
+                options.exact_title.as_deref(),
+                options.exact_app_id.as_deref(),
+            )];
+        }
+
+        Ok(matcher)
+    }
+
+    /// Returns the rules that apply to `window`.
+    ///
+    /// The result is empty if no rule matched or if the window was excluded. With
+    /// [`RuleEvaluation::First`] only the first matching rule is considered, with
+    /// [`RuleEvaluation::All`] every matching rule applies and any matching exclude rule takes
+    /// precedence over the others.
+    pub fn matching_rules(&self, window: &Window) -> Vec<&Rule> {
+        self.explain(window).applied
+    }
+
+    /// Checks every rule against `window` and explains the resulting decision.
+    pub fn explain(&self, window: &Window) -> Explanation<'_> {
+        let (width, height) = window.layout.window_size;
+
+        let fits = !(self.max_width.is_some_and(|max| width > max)
+            || self.max_height.is_some_and(|max| height > max));
+
+        let title_excluded = window.title.as_deref().is_some_and(|title| {
+            self.exclude_titles
+                .iter()
+                .any(|regex| regex.is_match(title))
+        });
+
+        let app_id = match window.app_id.as_deref() {
+            Some("") if self.empty_app_id == EmptyAppId::Missing => None,
+            app_id => app_id,
+        };
+
+        let checks: Vec<_> = self
+            .rules
+            .iter()
+            .map(|rule| (rule, rule.check(window, app_id, self.logic)))
+            .collect();
+
+        let mut matching = checks
+            .iter()
+            .filter(|(_, check)| fits && !title_excluded && check.matched())
+            .map(|&(rule, _)| rule);
+
+        let mut applied = match self.evaluation {
+            RuleEvaluation::First => match matching.next() {
+                Some(rule) if !rule.exclude => vec![rule],
+                _ => Vec::new(),
+            },
+            RuleEvaluation::All => {
+                let rules: Vec<_> = matching.collect();
+
+                if rules.iter().any(|rule| rule.exclude) {
+                    Vec::new()
+                } else {
+                    rules
+                }
+            }
+        };
+
+        // The default rule comes last, for the windows no rule matched, not even an exclude one
+        let fallback = self.fallback.as_ref().filter(|_| {
+            fits && !title_excluded && !checks.iter().any(|(_, check)| check.matched())
+        });
+        if let Some(rule) = fallback
+            && !rule.exclude
+        {
+            applied.push(rule);
+        }
+
+        Explanation {
+            window_id: window.id,
+            fits,
+            title_excluded,
+            checks,
+            applied,
+            fallback: fallback.is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use niri_ipc::WindowLayout;
+
+    use super::*;
+
+    fn window(title: &str, app_id: &str) -> Window {
+        Window {
+            id: 1,
+            title: Some(title.to_owned()),
+            app_id: Some(app_id.to_owned()),
+            pid: None,
+            workspace_id: Some(1),
+            is_focused: false,
+            is_floating: false,
+            is_urgent: false,
+            layout: WindowLayout {
+                pos_in_scrolling_layout: None,
+                tile_size: (100.0, 100.0),
+                window_size: (100, 100),
+                tile_pos_in_workspace_view: None,
+                window_offset_in_tile: (0.0, 0.0),
+            },
+            focus_timestamp: None,
+        }
+    }
+
+    fn matcher(config: &str) -> Matcher {
+        Matcher::new(&toml::from_str(config).unwrap()).unwrap()
+    }
+
+    /// The workspaces of the move rules applied to the window, in order.
+    fn targets(matcher: &Matcher, window: &Window) -> Vec<String> {
+        matcher
+            .matching_rules(window)
+            .into_iter()
+            .map(|rule| match rule.action {
+                RuleAction::Move(ref target) => target.clone(),
+                ref action => panic!("unexpected action {action:?}"),
+            })
+            .collect()
+    }
+
+    const OVERLAPPING: &str = r#"
+[[rule]]
+title = "^notes"
+action = "move"
+target_workspace = "first"
+
+[[rule]]
+app_id = "^editor$"
+action = "move"
+target_workspace = "second"
+"#;
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let matcher = matcher(OVERLAPPING);
+
+        assert_eq!(targets(&matcher, &window("notes", "editor")), ["first"]);
+        assert_eq!(targets(&matcher, &window("todo", "editor")), ["second"]);
+    }
+
+    #[test]
+    fn every_matching_rule_applies_in_order() {
+        let matcher = matcher(&format!("rule_evaluation = \"all\"\n{OVERLAPPING}"));
+
+        assert_eq!(
+            targets(&matcher, &window("notes", "editor")),
+            ["first", "second"]
+        );
+    }
+
+    #[test]
+    fn earlier_exclude_rule_wins() {
+        let matcher = matcher(&format!(
+            "[[rule]]\napp_id = \"^editor$\"\nexclude = true\n{OVERLAPPING}"
+        ));
+
+        assert!(targets(&matcher, &window("notes", "editor")).is_empty());
+        assert_eq!(targets(&matcher, &window("notes", "viewer")), ["first"]);
+    }
+
+    #[test]
+    fn later_exclude_rule_loses_to_first_match() {
+        let matcher = matcher(&format!(
+            "{OVERLAPPING}\n[[rule]]\napp_id = \"^editor$\"\nexclude = true"
+        ));
+
+        assert_eq!(targets(&matcher, &window("notes", "editor")), ["first"]);
+    }
+
+    #[test]
+    fn exclude_rule_wins_over_every_match() {
+        let matcher = matcher(&format!(
+            "rule_evaluation = \"all\"\n{OVERLAPPING}\n[[rule]]\napp_id = \"^editor$\"\nexclude = true"
+        ));
+
+        assert!(targets(&matcher, &window("notes", "editor")).is_empty());
+        assert_eq!(targets(&matcher, &window("notes", "viewer")), ["first"]);
+    }
+
+    #[test]
+    fn excluded_title_wins_over_rules() {
+        let matcher = matcher(&format!("exclude_titles = [\"secret\"]\n{OVERLAPPING}"));
+
+        assert!(targets(&matcher, &window("notes secret", "editor")).is_empty());
+    }
+
+    const WITH_DEFAULT: &str = r#"
+exclude_titles = ["secret"]
+
+[[rule]]
+title = "^notes"
+action = "move"
+target_workspace = "notes"
+
+[[rule]]
+app_id = "^private$"
+exclude = true
+
+[default_rule]
+action = "move"
+target_workspace = "other"
+"#;
+
+    #[test]
+    fn default_rule_applies_when_nothing_matched() {
+        let matcher = matcher(WITH_DEFAULT);
+
+        assert_eq!(targets(&matcher, &window("todo", "editor")), ["other"]);
+        assert!(matcher.explain(&window("todo", "editor")).fallback);
+    }
+
+    #[test]
+    fn default_rule_skipped_when_a_rule_matched() {
+        let matcher = matcher(WITH_DEFAULT);
+
+        assert_eq!(targets(&matcher, &window("notes", "editor")), ["notes"]);
+        assert!(
+            targets(&matcher, &window("todo", "private")).is_empty(),
+            "exclude rules match too"
+        );
+    }
+
+    #[test]
+    fn default_rule_skipped_for_excluded_titles() {
+        let matcher = matcher(WITH_DEFAULT);
+
+        assert!(targets(&matcher, &window("todo secret", "editor")).is_empty());
+    }
+
+    fn logic_matcher(config: &str, logic: MatchLogic) -> Matcher {
+        let options = Options {
+            match_logic: logic,
+            ..Options::default()
+        };
+
+        Matcher::from_options(&toml::from_str(config).unwrap(), &options).unwrap()
+    }
+
+    const TITLE_AND_APP_ID: &str = r#"
+[[rule]]
+title = "^notes$"
+app_id = "^editor$"
+"#;
+
+    #[test]
+    fn and_logic_requires_both() {
+        let matcher = logic_matcher(TITLE_AND_APP_ID, MatchLogic::And);
+
+        assert!(
+            matcher
+                .matching_rules(&window("notes", "viewer"))
+                .is_empty()
+        );
+        assert!(matcher.matching_rules(&window("todo", "editor")).is_empty());
+        assert!(
+            !matcher
+                .matching_rules(&window("notes", "editor"))
+                .is_empty()
+        );
+        assert!(matcher.matching_rules(&window("todo", "viewer")).is_empty());
+    }
+
+    #[test]
+    fn or_logic_takes_either() {
+        let matcher = logic_matcher(TITLE_AND_APP_ID, MatchLogic::Or);
+
+        assert!(
+            !matcher
+                .matching_rules(&window("notes", "viewer"))
+                .is_empty()
+        );
+        assert!(!matcher.matching_rules(&window("todo", "editor")).is_empty());
+        assert!(
+            !matcher
+                .matching_rules(&window("notes", "editor"))
+                .is_empty()
+        );
+        assert!(matcher.matching_rules(&window("todo", "viewer")).is_empty());
+    }
+
+    #[test]
+    fn logic_ignored_with_a_single_regex() {
+        for logic in [MatchLogic::And, MatchLogic::Or] {
+            let title_only = logic_matcher("[[rule]]\ntitle = \"^notes$\"", logic);
+            assert!(
+                !title_only
+                    .matching_rules(&window("notes", "viewer"))
+                    .is_empty()
+            );
+            assert!(
+                title_only
+                    .matching_rules(&window("todo", "viewer"))
+                    .is_empty()
+            );
+
+            let app_id_only = logic_matcher("[[rule]]\napp_id = \"^editor$\"", logic);
+            assert!(
+                !app_id_only
+                    .matching_rules(&window("todo", "editor"))
+                    .is_empty()
+            );
+            assert!(
+                app_id_only
+                    .matching_rules(&window("todo", "viewer"))
+                    .is_empty()
+            );
+        }
+    }
+
+    #[test]
+    fn missing_app_id_only_matches_by_title() {
+        let mut untitled_app = window("notes", "");
+        untitled_app.app_id = None;
+
+        let and = logic_matcher(TITLE_AND_APP_ID, MatchLogic::And);
+        assert!(!and.matching_rules(&untitled_app).is_empty());
+
+        let or = logic_matcher(TITLE_AND_APP_ID, MatchLogic::Or);
+        assert!(!or.matching_rules(&untitled_app).is_empty());
+        untitled_app.title = Some("todo".to_owned());
+        assert!(
+            or.matching_rules(&untitled_app).is_empty(),
+            "the app id regex doesn't accept windows without one"
+        );
+    }
+
+    #[test]
+    fn exact_options_use_the_logic() {
+        let options = Options {
+            exact_title: Some("notes".to_owned()),
+            exact_app_id: Some("editor".to_owned()),
+            match_logic: MatchLogic::Or,
+            ..Options::default()
+        };
+        let matcher = Matcher::from_options(&Config::default(), &options).unwrap();
+
+        assert!(
+            !matcher
+                .matching_rules(&window("notes", "viewer"))
+                .is_empty()
+        );
+        assert!(
+            matcher
+                .matching_rules(&window("notes!", "viewer"))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn windows_up_to_the_maximum_size_match() {
+        let matcher = matcher("max_width = 800\nmax_height = 600");
+        let mut pip = window("Picture-in-Picture", "firefox");
+
+        pip.layout.window_size = (800, 600);
+        assert!(!matcher.matching_rules(&pip).is_empty());
+
+        pip.layout.window_size = (801, 600);
+        assert!(matcher.matching_rules(&pip).is_empty());
+        assert!(!matcher.explain(&pip).fits);
+
+        pip.layout.window_size = (800, 601);
+        assert!(matcher.matching_rules(&pip).is_empty());
+    }
+
+    #[test]
+    fn windows_of_any_size_match_without_maximum() {
+        let matcher = matcher("");
+        let mut pip = window("Picture-in-Picture", "firefox");
+        pip.layout.window_size = (3840, 2160);
+
+        assert!(!matcher.matching_rules(&pip).is_empty());
+    }
+
+    #[test]
+    fn empty_app_id_is_missing_by_default() {
+        let matcher = matcher("");
+
+        assert!(
+            !matcher
+                .matching_rules(&window("Picture-in-Picture", ""))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn empty_app_id_must_match_when_strict() {
+        let matcher = matcher("empty_app_id = \"strict\"");
+
+        assert!(
+            matcher
+                .matching_rules(&window("Picture-in-Picture", ""))
+                .is_empty()
+        );
+        assert!(
+            !matcher
+                .matching_rules(&window("Picture-in-Picture", "firefox"))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn excluded_title_vetoes_a_matching_rule() {
+        let matcher = matcher(
+            "exclude_titles = [\"(?i)meeting\"]\n[[rule]]\ntitle = \"^Picture-in-Picture\"",
+        );
+        let pip = window("Picture-in-Picture", "firefox");
+        let mut meeting = pip.clone();
+        meeting.title = Some("Picture-in-Picture Meeting".to_owned());
+
+        assert!(!matcher.matching_rules(&pip).is_empty());
+        // The rule matches both, the exclusion takes precedence
+        let explanation = matcher.explain(&meeting);
+        assert!(explanation.checks[0].1.matched());
+        assert!(explanation.title_excluded);
+        assert!(matcher.matching_rules(&meeting).is_empty());
+    }
+
+    #[test]
+    fn exact_title_compares_whole_strings() {
+        let options = Options {
+            exact_title: Some("Picture-in-Picture".to_owned()),
+            ..Options::default()
+        };
+        let exact = Matcher::from_options(&Config::default(), &options).unwrap();
+        let regex = matcher("[[rule]]\ntitle = \"Picture-in-Picture\"");
+
+        let pip = window("Picture-in-Picture", "mpv");
+        assert!(!exact.matching_rules(&pip).is_empty());
+        assert!(!regex.matching_rules(&pip).is_empty());
+
+        // Regexes match anywhere in the title, exact titles only as a whole
+        let longer = window("Picture-in-Picture - Firefox", "mpv");
+        assert!(exact.matching_rules(&longer).is_empty());
+        assert!(!regex.matching_rules(&longer).is_empty());
+    }
+
+    #[test]
+    fn exact_app_id_takes_metacharacters_literally() {
+        let options = Options {
+            exact_app_id: Some("org.mpv.Mpv".to_owned()),
+            ..Options::default()
+        };
+        let exact = Matcher::from_options(&Config::default(), &options).unwrap();
+        let regex = matcher("[[rule]]\napp_id = \"org.mpv.Mpv\"");
+
+        let lookalike = window("video", "org-mpv-Mpv");
+        assert!(exact.matching_rules(&lookalike).is_empty());
+        assert!(!regex.matching_rules(&lookalike).is_empty());
+        assert!(
+            !exact
+                .matching_rules(&window("video", "org.mpv.Mpv"))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn exact_options_replace_the_rules() {
+        let options = Options {
+            exact_app_id: Some("mpv".to_owned()),
+            ..Options::default()
+        };
+        let exact = Matcher::from_options(&Config::default(), &options).unwrap();
+
+        assert!(
+            exact
+                .matching_rules(&window("Picture-in-Picture", "firefox"))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn require_floating_rejects_tiled_windows() {
+        let matcher = matcher("[[rule]]\napp_id = \"^mpv$\"\nrequire_floating = true");
+        let mut video = window("video", "mpv");
+
+        assert!(matcher.matching_rules(&video).is_empty());
+        video.is_floating = true;
+        assert!(!matcher.matching_rules(&video).is_empty());
+    }
+
+    #[test]
+    fn require_tiled_rejects_floating_windows() {
+        let matcher = matcher("[[rule]]\napp_id = \"^mpv$\"\nrequire_tiled = true");
+        let mut video = window("video", "mpv");
+
+        assert!(!matcher.matching_rules(&video).is_empty());
+        video.is_floating = true;
+        assert!(matcher.matching_rules(&video).is_empty());
+    }
+
+    #[test]
+    fn floating_state_ignored_by_default() {
+        let matcher = matcher("[[rule]]\napp_id = \"^mpv$\"");
+        let mut video = window("video", "mpv");
+
+        assert!(!matcher.matching_rules(&video).is_empty());
+        video.is_floating = true;
+        assert!(!matcher.matching_rules(&video).is_empty());
+    }
+
+    #[test]
+    fn floating_requirement_applies_with_or_logic() {
+        let matcher = logic_matcher(
+            "[[rule]]\ntitle = \"^video$\"\napp_id = \"^mpv$\"\nrequire_floating = true",
+            MatchLogic::Or,
+        );
+
+        assert!(matcher.matching_rules(&window("video", "vlc")).is_empty());
+    }
+}
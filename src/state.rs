@@ -0,0 +1,2761 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use niri_ipc::{Action, Event, Request, Response, Window, Workspace, WorkspaceReferenceArg};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{debug, info, trace, warn};
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::WindowId;
+use crate::cli::{BurstTarget, LandingPosition, Options, OverviewBehavior};
+use crate::config::{self, Config, ManualMove, NotifyEvent};
+use crate::control;
+use crate::ipc::RequestSink;
+use crate::matcher::{Matcher, RuleAction};
+
+/// Number of actions kept while the connection to niri is down, the oldest ones are dropped first.
+const MAX_PENDING_ACTIONS: usize = 16;
+
+/// Moves of a followed window niri can fail in a row before the window is assumed closed.
+const MAX_FAILED_MOVES: u32 = 3;
+
+/// Logs a move at the level set with `--move-log-level`.
+macro_rules! log_move {
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+
+        if level == LevelFilter::ERROR {
+            tracing::error!($($arg)+);
+        } else if level == LevelFilter::WARN {
+            tracing::warn!($($arg)+);
+        } else if level == LevelFilter::INFO {
+            tracing::info!($($arg)+);
+        } else if level == LevelFilter::DEBUG {
+            tracing::debug!($($arg)+);
+        } else if level == LevelFilter::TRACE {
+            tracing::trace!($($arg)+);
+        }
+    }};
+}
+
+/// A window that follows the focused workspace.
+#[derive(Debug)]
+struct Followed {
+    /// Title of the window, as last reported by niri.
+    title: Option<String>,
+    /// Workspace the window is on, as last reported by niri.
+    workspace_id: Option<u64>,
+    /// Whether focus follows the window when it is moved.
+    focus: bool,
+    /// Workspace the window was last moved to by piri.
+    last_move: Option<u64>,
+    /// Only move the window while it is on the workspace being switched away from.
+    same_workspace_as_focus: bool,
+    /// Whether following is enabled, it can be suspended through the control socket.
+    enabled: bool,
+    /// Output the window was last moved to by hand, it only follows workspaces there.
+    home_output: Option<String>,
+    /// Moves niri failed to perform since the last successful one.
+    failed_moves: u32,
+    /// How long to wait before moving the window.
+    timing: Timing,
+    /// When piri last moved the window.
+    last_moved_at: Option<Instant>,
+    /// Workspace the window is waiting to be moved to, and when.
+    scheduled: Option<(u64, Instant)>,
+}
+
+/// Delays applied to the moves of a followed window.
+#[derive(Debug, Default, Clone, Copy)]
+struct Timing {
+    /// Time workspace switches must settle for before the window is moved.
+    debounce: Duration,
+    /// Minimum time between two moves.
+    cooldown: Duration,
+}
+
+impl Followed {
+    fn new(window: &Window, focus: bool) -> Self {
+        Self {
+            title: window.title.clone(),
+            workspace_id: window.workspace_id,
+            focus,
+            last_move: None,
+            same_workspace_as_focus: false,
+            enabled: true,
+            home_output: None,
+            failed_moves: 0,
+            timing: Timing::default(),
+            last_moved_at: None,
+            scheduled: None,
+        }
+    }
+}
+
+/// Everything piri keeps track of, for bug reports, see the `dump-state` control command.
+#[derive(Debug, Serialize)]
+pub struct StateSnapshot {
+    version: &'static str,
+    paused: bool,
+    picked: bool,
+    overview_open: bool,
+    focused_workspace: Option<u64>,
+    left_workspace: Option<u64>,
+    pending_activation: Option<u64>,
+    overview_activation: Option<u64>,
+    overview_burst: Option<Vec<(u64, bool)>>,
+    focused_by_piri: Option<u64>,
+    /// Workspaces activated in the current burst of switches, with whether they got focus.
+    burst: Option<Vec<(u64, bool)>>,
+    actions: usize,
+    pending_actions: Vec<Action>,
+    followed: Vec<FollowedSnapshot>,
+    routed: Vec<u64>,
+    settling: Vec<u64>,
+    closing: Vec<u64>,
+    titles: BTreeMap<u64, Option<String>>,
+    workspaces: Vec<Workspace>,
+    /// The compiled rules, as printed by `--print-matchers`.
+    rules: String,
+    config: String,
+    options: String,
+}
+
+/// A followed window in a [`StateSnapshot`].
+#[derive(Debug, Serialize)]
+struct FollowedSnapshot {
+    id: u64,
+    title: Option<String>,
+    workspace_id: Option<u64>,
+    output: Option<String>,
+    focus: bool,
+    enabled: bool,
+    same_workspace_as_focus: bool,
+    last_move: Option<u64>,
+    home_output: Option<String>,
+    failed_moves: u32,
+    debounce_ms: u128,
+    cooldown_ms: u128,
+    last_moved_ms_ago: Option<u128>,
+    /// Workspace the window is waiting to be moved to, and in how many milliseconds.
+    scheduled: Option<(u64, u128)>,
+}
+
+/// Which followed windows follow to a workspace, by the output they are on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reach {
+    /// As set with `--follow-across-monitors` and `--follow-focus-monitor`.
+    Configured,
+    /// Only the windows on the output of the workspace.
+    SameOutput,
+    /// Every window, whichever output it is on.
+    AnyOutput,
+}
+
+/// Windows piri knows about and what it does with them.
+pub struct State {
+    options: Options,
+    config: Config,
+    matcher: Matcher,
+    paused: Arc<AtomicBool>,
+    /// Workspaces by id, as last reported by niri.
+    workspaces: HashMap<u64, Workspace>,
+    /// Windows moved to the focused workspace whenever it changes.
+    followed: HashMap<u64, Followed>,
+    /// Focused workspace, as last reported by niri.
+    focused_workspace: Option<u64>,
+    /// Workspace that was focused before the current switch.
+    left_workspace: Option<u64>,
+    /// Windows that are not matched again, either moved by a `move` rule or anchored after a
+    /// manual move.
+    routed: HashSet<u64>,
+    /// Titles of the open windows, as last reported by niri, to notice when they change.
+    titles: HashMap<u64, Option<String>>,
+    /// Windows matched by a log rule that were already logged.
+    logged: HashSet<u64>,
+    /// Focused workspace waiting for its switch to be confirmed, see [`Options::confirm_switch`].
+    pending_activation: Option<u64>,
+    /// Whether the followed window was picked by the user, in which case rules are ignored.
+    picked: bool,
+    overview_open: bool,
+    /// Workspace focused while moving was held back by the open overview.
+    overview_activation: Option<u64>,
+    /// Bursts of activations held back by the open overview, handled together once it closes.
+    overview_burst: Option<Vec<(u64, bool)>>,
+    /// Workspaces activated in the current burst of switches, with whether they got focus, and
+    /// when the burst is handled. See [`Options::activation_burst`].
+    burst: Option<(Vec<(u64, bool)>, Instant)>,
+    /// Workspace niri is about to focus because piri moved a window there along with focus.
+    focused_by_piri: Option<u64>,
+    /// Number of actions sent to niri.
+    actions: usize,
+    /// Actions that couldn't be sent, oldest first.
+    pending_actions: VecDeque<Action>,
+    /// Last status printed for waybar.
+    waybar_status: Option<String>,
+    /// Newly opened windows matching the rules, handled once they stayed open for
+    /// [`Options::match_settle`].
+    settling: HashMap<u64, (Window, Instant)>,
+    /// Closed followed windows, with their title, whose close is reacted to once no other window
+    /// replaced them for [`Options::match_settle`].
+    closing: HashMap<u64, (Option<String>, Instant)>,
+}
+
+impl State {
+    pub fn new(
+        options: Options,
+        config: Config,
+        matcher: Matcher,
+        paused: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            options,
+            config,
+            matcher,
+            paused,
+            workspaces: HashMap::new(),
+            focused_workspace: None,
+            left_workspace: None,
+            followed: HashMap::new(),
+            routed: HashSet::new(),
+            titles: HashMap::new(),
+            logged: HashSet::new(),
+            pending_activation: None,
+            picked: false,
+            overview_open: false,
+            overview_activation: None,
+            overview_burst: None,
+            burst: None,
+            focused_by_piri: None,
+            actions: 0,
+            pending_actions: VecDeque::new(),
+            waybar_status: None,
+            settling: HashMap::new(),
+            closing: HashMap::new(),
+        }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Whether any window was matched, by a rule or by picking it.
+    pub fn has_matches(&self) -> bool {
+        !self.followed.is_empty() || !self.routed.is_empty()
+    }
+
+    /// Number of actions sent to niri so far.
+    pub fn actions(&self) -> usize {
+        self.actions
+    }
+
+    /// Prints the status of the followed windows as a waybar custom module JSON line, if it changed
+    /// since the last time.
+    pub fn print_waybar(&mut self) {
+        let windows = self.describe_followed();
+
+        let (text, class) = if windows.is_empty() {
+            ("", "idle")
+        } else if self.paused.load(Ordering::Relaxed) {
+            ("\u{f04c}", "paused")
+        } else {
+            ("\u{f08d}", "tracking")
+        };
+
+        let tooltip = if windows.is_empty() {
+            "No window followed".to_owned()
+        } else {
+            windows.join("\n")
+        };
+
+        let status = serde_json::json!({
+            "text": text,
+            "tooltip": tooltip,
+            "class": class,
+        })
+        .to_string();
+
+        if self.waybar_status.as_ref() != Some(&status) {
+            println!("{status}");
+            self.waybar_status = Some(status);
+        }
+    }
+
+    /// Handles a command from the control socket, returning the reply.
+    pub fn control(
+        &mut self,
+        command: control::Command,
+        requests: &mut dyn RequestSink,
+    ) -> control::Reply {
+        match self.run_command(command, requests) {
+            Ok(payload) => control::Reply::Ok(payload),
+            Err(err) => control::Reply::Err(err.to_string()),
+        }
+    }
+
+    fn run_command(
+        &mut self,
+        command: control::Command,
+        requests: &mut dyn RequestSink,
+    ) -> Result<serde_json::Value> {
+        match command {
+            control::Command::Version => Ok(json!({
+                "protocol": control::PROTOCOL_VERSION,
+                "piri": env!("CARGO_PKG_VERSION"),
+            })),
+            control::Command::Status => {
+                let mut ids: Vec<_> = self.followed.keys().copied().collect();
+                ids.sort_unstable();
+
+                let windows: Vec<_> = ids
+                    .into_iter()
+                    .map(|id| {
+                        let followed = &self.followed[&id];
+                        json!({
+                            "id": id,
+                            "title": followed.title,
+                            "workspace": followed.workspace_id.and_then(|id| self.workspace_label(id)),
+                            "enabled": followed.enabled,
+                        })
+                    })
+                    .collect();
+
+                Ok(json!({
+                    "paused": self.paused.load(Ordering::Relaxed),
+                    "windows": windows,
+                }))
+            }
+            control::Command::Tracked => {
+                let mut ids: Vec<_> = self.followed.keys().copied().collect();
+                ids.sort_unstable();
+                Ok(json!(ids))
+            }
+            control::Command::Follow { window_id, enabled } => {
+                let Some(followed) = self.followed.get_mut(&window_id) else {
+                    bail!("{} is not followed", WindowId(window_id));
+                };
+
+                followed.enabled = enabled;
+                info!(
+                    "{} following {}",
+                    if enabled { "Resumed" } else { "Suspended" },
+                    WindowId(window_id)
+                );
+                Ok(serde_json::Value::Null)
+            }
+            control::Command::Track { window_id } => {
+                if self.followed.contains_key(&window_id) {
+                    bail!("{} is already followed", WindowId(window_id));
+                }
+
+                let Ok(Response::Windows(windows)) = requests.send(Request::Windows)? else {
+                    bail!("Failed to fetch the open windows");
+                };
+                let Some(window) = windows.iter().find(|window| window.id == window_id) else {
+                    bail!("There is no {}", WindowId(window_id));
+                };
+
+                self.routed.remove(&window_id);
+                self.followed.insert(
+                    window_id,
+                    Followed {
+                        timing: self.timing(None, None),
+                        ..Followed::new(window, self.options.focus)
+                    },
+                );
+                info!("Following {}", WindowId(window_id));
+                self.apply_match_actions(window_id, requests)?;
+                Ok(serde_json::Value::Null)
+            }
+            control::Command::Untrack { window_id } => {
+                if self.followed.remove(&window_id).is_none() {
+                    bail!("{} is not followed", WindowId(window_id));
+                }
+
+                // Keep the rules from matching it again
+                self.routed.insert(window_id);
+                info!("Stopped following {}", WindowId(window_id));
+                Ok(serde_json::Value::Null)
+            }
+            control::Command::Pause => {
+                if !self.paused.swap(true, Ordering::Relaxed) {
+                    info!("Paused, windows will not be moved until resumed");
+                }
+                Ok(serde_json::Value::Null)
+            }
+            control::Command::Resume => {
+                if self.paused.swap(false, Ordering::Relaxed) {
+                    info!("Resumed moving windows");
+                }
+                Ok(serde_json::Value::Null)
+            }
+            control::Command::Reload => {
+                let config = Config::from_options(&self.options)?;
+                self.matcher = Matcher::from_options(&config, &self.options)?;
+                self.config = config;
+                info!("Reloaded the config");
+                Ok(serde_json::Value::Null)
+            }
+            control::Command::Rescan => {
+                let Ok(Response::Windows(windows)) = requests.send(Request::Windows)? else {
+                    bail!("Failed to fetch the open windows");
+                };
+
+                let before = self.followed.len() + self.routed.len();
+                self.scan(windows, requests)?;
+                let matched = (self.followed.len() + self.routed.len()).saturating_sub(before);
+
+                Ok(json!({ "matched": matched }))
+            }
+            control::Command::DumpState => Ok(serde_json::to_value(self.snapshot())?),
+            control::Command::Explain { window_id } => {
+                let Ok(Response::Windows(windows)) = requests.send(Request::Windows)? else {
+                    bail!("Failed to fetch the open windows");
+                };
+                let Some(window) = windows.iter().find(|window| window.id == window_id) else {
+                    bail!("There is no {}", WindowId(window_id));
+                };
+
+                let explanation = self.matcher.explain(window);
+                info!("{}", explanation);
+
+                let mut reply = explanation.json();
+                reply["tracked"] = json!(self.is_handled(window_id));
+                Ok(reply)
+            }
+        }
+    }
+
+    /// Describes every followed window on its own line, sorted by window id.
+    fn describe_followed(&self) -> Vec<String> {
+        let mut ids: Vec<_> = self.followed.keys().copied().collect();
+        ids.sort_unstable();
+
+        ids.into_iter()
+            .map(|id| {
+                let followed = &self.followed[&id];
+                let workspace = followed
+                    .workspace_id
+                    .and_then(|id| self.workspace_label(id));
+
+                let mut line = match workspace {
+                    Some(workspace) => format!("Window {id} on workspace {workspace}"),
+                    None => format!("Window {id}"),
+                };
+                if !followed.enabled {
+                    line.push_str(" (suspended)");
+                }
+
+                line
+            })
+            .collect()
+    }
+
+    /// Describes the whole state, for the `dump-state` control command.
+    pub fn snapshot(&self) -> StateSnapshot {
+        fn sorted(ids: impl IntoIterator<Item = u64>) -> Vec<u64> {
+            let mut ids: Vec<_> = ids.into_iter().collect();
+            ids.sort_unstable();
+            ids
+        }
+
+        let now = Instant::now();
+        let mut followed: Vec<_> = self
+            .followed
+            .iter()
+            .map(|(&id, followed)| FollowedSnapshot {
+                id,
+                title: followed.title.clone(),
+                workspace_id: followed.workspace_id,
+                output: self.output_of(followed.workspace_id).map(str::to_owned),
+                focus: followed.focus,
+                enabled: followed.enabled,
+                same_workspace_as_focus: followed.same_workspace_as_focus,
+                last_move: followed.last_move,
+                home_output: followed.home_output.clone(),
+                failed_moves: followed.failed_moves,
+                debounce_ms: followed.timing.debounce.as_millis(),
+                cooldown_ms: followed.timing.cooldown.as_millis(),
+                last_moved_ms_ago: followed
+                    .last_moved_at
+                    .map(|at| now.saturating_duration_since(at).as_millis()),
+                scheduled: followed.scheduled.map(|(workspace, due)| {
+                    (workspace, due.saturating_duration_since(now).as_millis())
+                }),
+            })
+            .collect();
+        followed.sort_unstable_by_key(|followed| followed.id);
+
+        let mut workspaces: Vec<_> = self.workspaces.values().cloned().collect();
+        workspaces.sort_unstable_by_key(|workspace| workspace.id);
+
+        StateSnapshot {
+            version: env!("CARGO_PKG_VERSION"),
+            paused: self.paused.load(Ordering::Relaxed),
+            picked: self.picked,
+            overview_open: self.overview_open,
+            focused_workspace: self.focused_workspace,
+            left_workspace: self.left_workspace,
+            pending_activation: self.pending_activation,
+            overview_activation: self.overview_activation,
+            overview_burst: self.overview_burst.clone(),
+            focused_by_piri: self.focused_by_piri,
+            burst: self
+                .burst
+                .as_ref()
+                .map(|(activations, _)| activations.clone()),
+            actions: self.actions,
+            pending_actions: self.pending_actions.iter().cloned().collect(),
+            followed,
+            routed: sorted(self.routed.iter().copied()),
+            settling: sorted(self.settling.keys().copied()),
+            closing: sorted(self.closing.keys().copied()),
+            titles: self
+                .titles
+                .iter()
+                .map(|(&id, title)| (id, title.clone()))
+                .collect(),
+            workspaces,
+            rules: self.matcher.to_string(),
+            config: format!("{:?}", self.config),
+            options: format!("{:?}", self.options),
+        }
+    }
+
+    /// Name of a workspace, or its index if it has none.
+    fn workspace_label(&self, id: u64) -> Option<String> {
+        let workspace = self.workspaces.get(&id)?;
+
+        Some(match self.workspace_name(id) {
+            Some(name) => name.to_owned(),
+            None => workspace.idx.to_string(),
+        })
+    }
+
+    /// Follows exactly this window for the rest of the session, ignoring the rules.
+    pub fn pick(&mut self, window: &Window) {
+        self.followed.insert(
+            window.id,
+            Followed {
+                timing: self.timing(None, None),
+                ..Followed::new(window, self.options.focus)
+            },
+        );
+        self.picked = true;
+    }
+
+    /// Matches the windows that were already open when piri started or reconnected, returning how
+    /// many of them are handled afterwards, including the ones matched by an earlier scan.
+    ///
+    /// niri hands out new ids after a restart, so the windows that aren't open anymore are
+    /// forgotten first and their replacements matched by the rules like any other window. The
+    /// workspaces are fetched first, their event only comes later and targets need them to tell
+    /// ids from names.
+    pub fn scan(&mut self, windows: Vec<Window>, requests: &mut dyn RequestSink) -> Result<usize> {
+        if let Ok(Response::Workspaces(workspaces)) = requests.send(Request::Workspaces)? {
+            self.workspaces_changed(workspaces);
+        }
+        self.forget_closed(&windows);
+
+        let mut matched = 0;
+
+        for window in windows {
+            self.titles.insert(window.id, window.title.clone());
+
+            if self.is_handled(window.id) {
+                matched += 1;
+                continue;
+            }
+
+            if self.match_window(&window, requests)? {
+                info!("Found a matching window, {}", WindowId(window.id));
+                matched += 1;
+            } else {
+                match window.title {
+                    Some(title) => debug!("Ignoring {} \"{}\"", WindowId(window.id), title),
+                    None => debug!("Ignoring {}", WindowId(window.id)),
+                }
+            }
+        }
+
+        Ok(matched)
+    }
+
+    pub fn handle_event(&mut self, event: Event, requests: &mut dyn RequestSink) -> Result<()> {
+        // Any event other than another activation means the compositor moved on from the switch
+        if !matches!(event, Event::WorkspaceActivated { .. })
+            && let Some(id) = self.pending_activation.take()
+        {
+            debug!("Switch to workspace {} confirmed", id);
+            self.follow_to(id, Reach::Configured, requests)?;
+        }
+
+        match event {
+            Event::WorkspaceActivated { id, focused } => {
+                // Only the first of several pending switches leaves the original workspace
+                if focused
+                    && self.pending_activation.is_none()
+                    && self.overview_activation.is_none()
+                    && self.overview_burst.is_none()
+                {
+                    self.left_workspace = self.focused_workspace;
+                }
+                if focused {
+                    self.focused_workspace = Some(id);
+                }
+                self.workspace_activated(id, focused);
+
+                // Following it would move the window again, possibly focusing yet another
+                // workspace with a relative offset
+                let by_piri = focused && self.focused_by_piri.take() == Some(id);
+
+                if by_piri {
+                    debug!(
+                        "Workspace {} was focused by moving a window there, not following",
+                        id
+                    );
+                } else if !self.options.activation_burst.is_zero() {
+                    let (activations, _) = self.burst.get_or_insert_with(|| {
+                        (Vec::new(), Instant::now() + self.options.activation_burst)
+                    });
+                    activations.push((id, focused));
+                } else if focused && self.options.confirm_switch {
+                    debug!("Workspace {} focused, waiting for the switch to settle", id);
+                    self.pending_activation = Some(id);
+                } else if focused
+                    && self.overview_open
+                    && self.options.overview_behavior == OverviewBehavior::Pause
+                {
+                    debug!(
+                        "Workspace {} focused in the overview, waiting for it to close",
+                        id
+                    );
+                    self.overview_activation = Some(id);
+                } else if focused {
+                    self.follow_to(id, Reach::Configured, requests)?;
+                } else {
+                    debug!("Workspace {} activated but not focused", id);
+                }
+            }
+            Event::WorkspacesChanged { workspaces } => self.workspaces_changed(workspaces),
+            Event::WorkspaceUrgencyChanged { id, urgent } => {
+                if let Some(workspace) = self.workspaces.get_mut(&id) {
+                    workspace.is_urgent = urgent;
+                }
+            }
+            Event::WorkspaceActiveWindowChanged {
+                workspace_id,
+                active_window_id,
+            } => {
+                if let Some(workspace) = self.workspaces.get_mut(&workspace_id) {
+                    workspace.active_window_id = active_window_id;
+                }
+            }
+            Event::WindowOpenedOrChanged { ref window } => {
+                if self.options.explain {
+                    info!("{}", self.matcher.explain(window));
+                }
+
+                self.window_changed(window, requests)?;
+            }
+            // Sent at the start of every event stream, which brings the tracked windows up to
+            // date after a reconnect
+            Event::WindowsChanged { windows } => self.reconcile(&windows, requests)?,
+            Event::WindowClosed { id } => self.window_closed(id),
+            Event::WindowUrgencyChanged { id, urgent: true } if self.options.follow_urgent => {
+                self.follow_urgent(id, requests)?;
+            }
+            Event::OverviewOpenedOrClosed { is_open } => {
+                self.overview_open = is_open;
+
+                if !is_open && let Some(id) = self.overview_activation.take() {
+                    debug!("Overview closed on workspace {}", id);
+                    self.follow_to(id, Reach::Configured, requests)?;
+                }
+                if !is_open && let Some(activations) = self.overview_burst.take() {
+                    debug!("Overview closed after {} activations", activations.len());
+                    self.follow_burst(activations, requests)?;
+                }
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Brings the tracked windows up to date with the full list of open windows.
+    pub fn reconcile(&mut self, windows: &[Window], requests: &mut dyn RequestSink) -> Result<()> {
+        self.forget_closed(windows);
+
+        for window in windows {
+            self.window_changed(window, requests)?;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets about the windows missing from the full list of open windows.
+    fn forget_closed(&mut self, windows: &[Window]) {
+        let open: HashSet<_> = windows.iter().map(|window| window.id).collect();
+
+        let closed: Vec<_> = self
+            .followed
+            .keys()
+            .filter(|id| !open.contains(id))
+            .copied()
+            .collect();
+        for id in closed {
+            self.window_closed(id);
+        }
+        self.routed.retain(|id| open.contains(id));
+        self.titles.retain(|id, _| open.contains(id));
+        self.settling.retain(|id, _| open.contains(id));
+        self.logged.retain(|id| open.contains(id));
+    }
+
+    /// Handles workspace switches on several monitors at once, in the order they happened.
+    fn follow_burst(
+        &mut self,
+        activations: Vec<(u64, bool)>,
+        requests: &mut dyn RequestSink,
+    ) -> Result<()> {
+        if self.overview_open && self.options.overview_behavior == OverviewBehavior::Pause {
+            debug!(
+                "{} workspace activations in the overview, waiting for it to close",
+                activations.len()
+            );
+            self.overview_burst
+                .get_or_insert_with(Vec::new)
+                .extend(activations);
+            return Ok(());
+        }
+
+        debug!(
+            "Handling a burst of {} workspace activations",
+            activations.len()
+        );
+
+        match self.options.burst_target {
+            BurstTarget::FocusedMonitor => {
+                if let Some(&(id, _)) = activations.iter().rev().find(|&&(_, focused)| focused) {
+                    self.follow_to(id, Reach::AnyOutput, requests)?;
+                }
+            }
+            BurstTarget::WindowMonitor => {
+                // Only the last workspace activated on every output counts
+                let mut targets: Vec<u64> = Vec::new();
+                for &(id, _) in activations.iter().rev() {
+                    let output = self.output_of(Some(id));
+                    if !targets
+                        .iter()
+                        .any(|&target| self.output_of(Some(target)) == output)
+                    {
+                        targets.push(id);
+                    }
+                }
+
+                for id in targets.into_iter().rev() {
+                    self.follow_to(id, Reach::SameOutput, requests)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rebuilds the known workspaces from the full list niri sent, the finer workspace events
+    /// keep them up to date in between.
+    fn workspaces_changed(&mut self, workspaces: Vec<Workspace>) {
+        self.focused_workspace = workspaces
+            .iter()
+            .find(|workspace| workspace.is_focused)
+            .map(|workspace| workspace.id);
+        self.workspaces = workspaces
+            .into_iter()
+            .map(|workspace| (workspace.id, workspace))
+            .collect();
+    }
+
+    /// Moves the followed windows to the focused workspace right after the scan, for
+    /// `--follow-on-scan`.
+    pub fn follow_after_scan(&mut self, requests: &mut dyn RequestSink) -> Result<()> {
+        if !self.options.follow_on_scan || self.followed.is_empty() {
+            return Ok(());
+        }
+
+        match self.focused_workspace {
+            Some(focused) => {
+                debug!(
+                    "Following to the focused workspace {} after the scan",
+                    focused
+                );
+                self.follow_to(focused, Reach::Configured, requests)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the followed windows that `reach` the newly focused workspace there.
+    fn follow_to(
+        &mut self,
+        focused: u64,
+        reach: Reach,
+        requests: &mut dyn RequestSink,
+    ) -> Result<()> {
+        let workspace_id = self.offset_workspace(focused);
+        if workspace_id != focused {
+            debug!(
+                "Workspace {} focused, following to workspace {} at offset {}",
+                focused, workspace_id, self.options.relative_offset
+            );
+        }
+
+        if self.followed.is_empty() {
+            debug!(
+                "Workspace {} focused but no window was detected",
+                workspace_id
+            );
+        } else if self.paused.load(Ordering::Relaxed) {
+            debug!("Workspace {} focused but moving is paused", workspace_id);
+        } else if !self.config.follow_scheduled_now() {
+            debug!(
+                "Workspace {} focused but following is outside of its schedule",
+                workspace_id
+            );
+        } else if !self.options.follow_special && self.is_special(workspace_id) {
+            debug!(
+                "Workspace {} focused but it is a special workspace",
+                workspace_id
+            );
+        } else {
+            let mut moves = Vec::new();
+
+            for (&window, followed) in &self.followed {
+                if !followed.enabled {
+                    debug!("Following {} is suspended", WindowId(window));
+                    continue;
+                }
+
+                // The same activation can be seen twice around a reconnect
+                if followed.workspace_id == Some(workspace_id) {
+                    debug!(
+                        "{} is already on workspace {}",
+                        WindowId(window),
+                        workspace_id
+                    );
+                    continue;
+                }
+
+                // Back to back activations of the same workspace can arrive before niri reports
+                // the window on its new workspace
+                if followed.last_move == Some(workspace_id) {
+                    debug!(
+                        "{} was already moved to workspace {}",
+                        WindowId(window),
+                        workspace_id
+                    );
+                    continue;
+                }
+
+                // Across monitors, a window dragged to another output stays anchored there
+                let home_output = followed
+                    .home_output
+                    .as_deref()
+                    .filter(|&output| self.has_output(output));
+                let window_output = match reach {
+                    Reach::AnyOutput => None,
+                    Reach::SameOutput => self.output_of(followed.workspace_id),
+                    Reach::Configured if self.options.follow_focus_monitor => None,
+                    Reach::Configured if self.options.follow_across_monitors => home_output,
+                    Reach::Configured => self.output_of(followed.workspace_id),
+                };
+
+                if let Some(from) = window_output
+                    && let Some(to) = self.output_of(Some(workspace_id))
+                    && from != to
+                {
+                    debug!(
+                        "{} is on output {}, not following to {}",
+                        WindowId(window),
+                        from,
+                        to
+                    );
+                    continue;
+                }
+
+                if followed.same_workspace_as_focus && followed.workspace_id != self.left_workspace
+                {
+                    debug!(
+                        "{} is not on the workspace that was switched away from",
+                        WindowId(window)
+                    );
+                    continue;
+                }
+
+                // Moves wait for the switches to settle, and for the cooldown of the last move
+                let now = Instant::now();
+                let due = followed
+                    .last_moved_at
+                    .map(|at| at + followed.timing.cooldown)
+                    .into_iter()
+                    .chain([now + followed.timing.debounce])
+                    .max()
+                    .unwrap_or(now);
+
+                moves.push((window, due));
+            }
+
+            for (window, due) in moves {
+                if due > Instant::now() {
+                    debug!(
+                        "Moving {} to workspace {} in {:?}",
+                        WindowId(window),
+                        workspace_id,
+                        due.saturating_duration_since(Instant::now())
+                    );
+                    if let Some(followed) = self.followed.get_mut(&window) {
+                        followed.scheduled = Some((workspace_id, due));
+                    }
+                    continue;
+                }
+
+                self.move_followed(window, workspace_id, requests)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// When the next delayed move is due, if any.
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.followed
+            .values()
+            .filter_map(|followed| followed.scheduled.map(|(_, due)| due))
+            .chain(self.settling.values().map(|&(_, due)| due))
+            .chain(self.closing.values().map(|&(_, due)| due))
+            .chain(self.burst.as_ref().map(|&(_, due)| due))
+            .min()
+    }
+
+    /// Performs the delayed moves that are due, unless the workspace they were waiting for lost
+    /// focus in the meantime, and handles the windows that settled.
+    pub fn run_timers(&mut self, requests: &mut dyn RequestSink) -> Result<()> {
+        let now = Instant::now();
+
+        let settled: Vec<_> = self
+            .settling
+            .iter()
+            .filter(|&(_, &(_, due))| due <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in settled {
+            if let Some((window, _)) = self.settling.remove(&id)
+                && self.match_window(&window, requests)?
+            {
+                info!("{} matched regexs", WindowId(window.id));
+            }
+        }
+
+        let closed: Vec<_> = self
+            .closing
+            .iter()
+            .filter(|&(_, &(_, due))| due <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in closed {
+            if let Some((title, _)) = self.closing.remove(&id) {
+                self.react_to_close(id, title.as_deref());
+            }
+        }
+
+        if self.burst.as_ref().is_some_and(|&(_, due)| due <= now)
+            && let Some((activations, _)) = self.burst.take()
+        {
+            self.follow_burst(activations, requests)?;
+        }
+
+        let mut due = Vec::new();
+
+        for (&window, followed) in &mut self.followed {
+            if let Some((workspace_id, at)) = followed.scheduled
+                && at <= now
+            {
+                followed.scheduled = None;
+                due.push((window, workspace_id));
+            }
+        }
+
+        for (window, workspace_id) in due {
+            // A burst of switches can also send windows to active workspaces that aren't focused
+            let active = !self.options.activation_burst.is_zero()
+                && self.options.burst_target == BurstTarget::WindowMonitor
+                && self
+                    .workspaces
+                    .get(&workspace_id)
+                    .is_some_and(|workspace| workspace.is_active);
+
+            if !active
+                && self
+                    .focused_workspace
+                    .map(|focused| self.offset_workspace(focused))
+                    != Some(workspace_id)
+            {
+                debug!(
+                    "Workspace {} is no longer focused, not moving {}",
+                    workspace_id,
+                    WindowId(window)
+                );
+            } else if self.paused.load(Ordering::Relaxed) {
+                debug!("Not moving {} as moving is paused", WindowId(window));
+            } else if !self.config.follow_scheduled_now() {
+                debug!(
+                    "Not moving {} as following is outside of its schedule",
+                    WindowId(window)
+                );
+            } else {
+                self.move_followed(window, workspace_id, requests)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Brings a followed window that demands attention to the focused workspace, whatever would
+    /// otherwise keep it from being moved.
+    fn follow_urgent(&mut self, window: u64, requests: &mut dyn RequestSink) -> Result<()> {
+        let Some(followed) = self.followed.get(&window) else {
+            return Ok(());
+        };
+        let Some(focused) = self.focused_workspace else {
+            return Ok(());
+        };
+
+        if followed.workspace_id == Some(focused) {
+            debug!(
+                "{} is urgent and already on the focused workspace",
+                WindowId(window)
+            );
+            return Ok(());
+        }
+
+        debug!(
+            "{} is urgent, moving it to workspace {}",
+            WindowId(window),
+            focused
+        );
+        self.move_followed(window, focused, requests)
+    }
+
+    /// Moves a followed window to the workspace, updating what is known about its moves.
+    fn move_followed(
+        &mut self,
+        window: u64,
+        workspace_id: u64,
+        requests: &mut dyn RequestSink,
+    ) -> Result<()> {
+        let Some(from) = self
+            .followed
+            .get(&window)
+            .map(|followed| followed.workspace_id)
+        else {
+            return Ok(());
+        };
+        let target = self.landing_workspace(from, workspace_id);
+        let Some(followed) = self.followed.get_mut(&window) else {
+            return Ok(());
+        };
+
+        followed.last_move = Some(target);
+        followed.last_moved_at = Some(Instant::now());
+        followed.scheduled = None;
+        let (focus, from, title) = (
+            followed.focus,
+            followed.workspace_id,
+            followed.title.clone(),
+        );
+
+        if focus {
+            self.expect_focus(target);
+        }
+
+        match from {
+            Some(from) => log_move!(
+                self.options.move_log_level,
+                "Workspace {} focused. Moving {} from workspace {} to workspace {}",
+                workspace_id,
+                WindowId(window),
+                from,
+                target
+            ),
+            None => log_move!(
+                self.options.move_log_level,
+                "Workspace {} focused. Moving {} to workspace {}",
+                workspace_id,
+                WindowId(window),
+                target
+            ),
+        }
+
+        let moved = self.send_action(
+            Action::MoveWindowToWorkspace {
+                window_id: Some(window),
+                reference: WorkspaceReferenceArg::Id(target),
+                focus,
+            },
+            requests,
+        )?;
+
+        // The close event may have been missed, e.g. while reconnecting
+        if let Some(followed) = self.followed.get_mut(&window) {
+            if moved {
+                followed.failed_moves = 0;
+            } else {
+                // Moving it there again isn't a repeat if it never got there
+                followed.last_move = None;
+                followed.failed_moves += 1;
+
+                if followed.failed_moves >= MAX_FAILED_MOVES {
+                    info!(
+                        "{} seems to be gone after {} failed moves, no longer following it",
+                        WindowId(window),
+                        followed.failed_moves
+                    );
+                    self.followed.remove(&window);
+                }
+                return Ok(());
+            }
+        }
+
+        self.land(window, focus, requests)?;
+        self.notify(
+            NotifyEvent::Move,
+            window,
+            title.as_deref(),
+            &target.to_string(),
+        );
+
+        Ok(())
+    }
+
+    /// Resolves the delays of a followed window from its rule and the config.
+    fn timing(&self, debounce: Option<Duration>, cooldown: Option<Duration>) -> Timing {
+        Timing {
+            debounce: debounce.unwrap_or(Duration::from_millis(self.config.debounce_ms)),
+            cooldown: cooldown.unwrap_or(Duration::from_millis(self.config.cooldown_ms)),
+        }
+    }
+
+    /// Brings the active and focused flags of the known workspaces up to date.
+    fn workspace_activated(&mut self, id: u64, focused: bool) {
+        let Some(output) = self
+            .workspaces
+            .get(&id)
+            .map(|workspace| workspace.output.clone())
+        else {
+            return;
+        };
+
+        for workspace in self.workspaces.values_mut() {
+            if workspace.output == output {
+                workspace.is_active = workspace.id == id;
+            }
+            if focused {
+                workspace.is_focused = workspace.id == id;
+            }
+        }
+    }
+
+    /// Replaces a workspace id in the action with the name of the workspace, which
+    /// `--emit-script` can print since `niri msg` takes no ids. Indices would refer to the
+    /// focused output, so `None` is returned for unnamed workspaces.
+    fn without_workspace_id(&self, action: Action) -> Option<Action> {
+        match action {
+            Action::MoveWindowToWorkspace {
+                window_id,
+                reference: WorkspaceReferenceArg::Id(id),
+                focus,
+            } => Some(Action::MoveWindowToWorkspace {
+                window_id,
+                reference: WorkspaceReferenceArg::Name(self.workspace_name(id)?.to_owned()),
+                focus,
+            }),
+            action => Some(action),
+        }
+    }
+
+    /// Name of the workspace, if it has one.
+    fn workspace_name(&self, workspace_id: u64) -> Option<&str> {
+        self.workspaces.get(&workspace_id)?.name.as_deref()
+    }
+
+    /// Name of the output showing the workspace, if known.
+    fn output_of(&self, workspace_id: Option<u64>) -> Option<&str> {
+        self.workspaces.get(&workspace_id?)?.output.as_deref()
+    }
+
+    /// The workspace `--relative-offset` positions away from `focused` on its output, clamped to
+    /// the first and last ones or wrapped around with `--relative-wrap`.
+    fn offset_workspace(&self, focused: u64) -> u64 {
+        let Some(workspace) = self.workspaces.get(&focused) else {
+            return focused;
+        };
+        if self.options.relative_offset == 0 {
+            return focused;
+        }
+
+        let mut siblings: Vec<_> = self
+            .workspaces
+            .values()
+            .filter(|sibling| sibling.output == workspace.output)
+            .collect();
+        siblings.sort_unstable_by_key(|sibling| sibling.idx);
+
+        let position = siblings
+            .iter()
+            .position(|sibling| sibling.id == focused)
+            .unwrap_or_default();
+        // The focused workspace is one of them, so there is at least one
+        let count = siblings.len() as i64;
+        let target = position as i64 + i64::from(self.options.relative_offset);
+        let target = if self.options.relative_wrap {
+            target.rem_euclid(count)
+        } else {
+            target.clamp(0, count - 1)
+        };
+
+        siblings[target as usize].id
+    }
+
+    /// Remembers that a move with focus will make niri focus the workspace, unless it already is.
+    fn expect_focus(&mut self, workspace_id: u64) {
+        if self.focused_workspace != Some(workspace_id) {
+            self.focused_by_piri = Some(workspace_id);
+        }
+    }
+
+    /// The workspace a target written in the config or on the command line refers to.
+    fn workspace_reference(&self, target: &str) -> WorkspaceReferenceArg {
+        config::parse_workspace(target, self.workspaces.keys().copied())
+    }
+
+    /// Finds the workspace a reference points to, indexes being relative to the focused output.
+    fn resolve_workspace(&self, reference: &WorkspaceReferenceArg) -> Option<u64> {
+        let focused_output = self.output_of(self.focused_workspace);
+
+        self.workspaces
+            .values()
+            .find(|workspace| match reference {
+                WorkspaceReferenceArg::Id(id) => workspace.id == *id,
+                WorkspaceReferenceArg::Index(index) => {
+                    workspace.idx == *index && workspace.output.as_deref() == focused_output
+                }
+                WorkspaceReferenceArg::Name(name) => workspace.name.as_ref() == Some(name),
+            })
+            .map(|workspace| workspace.id)
+    }
+
+    /// Whether any workspace is currently on the output.
+    fn has_output(&self, output: &str) -> bool {
+        self.workspaces
+            .values()
+            .any(|workspace| workspace.output.as_deref() == Some(output))
+    }
+
+    /// The workspace a window moving from `from` to `to` lands on. Windows followed onto another
+    /// output land on the workspace `landing_workspaces` lists for it, if it has one.
+    fn landing_workspace(&self, from: Option<u64>, to: u64) -> u64 {
+        let Some(output) = self.output_of(Some(to)) else {
+            return to;
+        };
+        if self.output_of(from).is_none_or(|from| from == output) {
+            return to;
+        }
+        let Some(landing) = self.config.landing_workspaces.get(output) else {
+            return to;
+        };
+
+        let reference = self.workspace_reference(landing);
+        let landing = self
+            .workspaces
+            .values()
+            .filter(|workspace| workspace.output.as_deref() == Some(output))
+            .find(|workspace| match reference {
+                WorkspaceReferenceArg::Id(id) => workspace.id == id,
+                WorkspaceReferenceArg::Name(ref name) => workspace.name.as_ref() == Some(name),
+                // `workspace_reference` only gives ids and names
+                WorkspaceReferenceArg::Index(_) => false,
+            })
+            .map(|workspace| workspace.id);
+
+        match landing {
+            Some(landing) => landing,
+            None => {
+                debug!(
+                    "Landing workspace {:?} is not on output {}, landing on workspace {}",
+                    self.config.landing_workspaces[output], output, to
+                );
+                to
+            }
+        }
+    }
+
+    /// Whether the workspace is named in the `special_workspaces` config.
+    fn is_special(&self, workspace_id: u64) -> bool {
+        self.workspace_name(workspace_id).is_some_and(|name| {
+            self.config
+                .special_workspaces
+                .iter()
+                .any(|special| special == name)
+        })
+    }
+
+    /// Places a window that was just moved according to [`Options::landing_position`].
+    fn land(&mut self, window: u64, focused: bool, requests: &mut dyn RequestSink) -> Result<()> {
+        let column_action = match self.options.landing_position {
+            LandingPosition::Natural => return Ok(()),
+            LandingPosition::FloatingCenter => {
+                self.send_action(Action::MoveWindowToFloating { id: Some(window) }, requests)?;
+                self.send_action(Action::CenterWindow { id: Some(window) }, requests)?;
+                return Ok(());
+            }
+            LandingPosition::LastColumn => Action::MoveColumnToLast {},
+            LandingPosition::FirstColumn => Action::MoveColumnToFirst {},
+        };
+
+        self.send_action(Action::MoveWindowToTiling { id: Some(window) }, requests)?;
+
+        // Columns can only be moved while focused, so focus goes back afterwards
+        let previous = if focused {
+            None
+        } else {
+            match requests.send(Request::FocusedWindow)? {
+                Ok(Response::FocusedWindow(Some(previous))) if previous.id != window => {
+                    Some(previous.id)
+                }
+                _ => None,
+            }
+        };
+
+        self.send_action(Action::FocusWindow { id: window }, requests)?;
+        self.send_action(column_action, requests)?;
+
+        if let Some(previous) = previous {
+            self.send_action(Action::FocusWindow { id: previous }, requests)?;
+        }
+
+        Ok(())
+    }
+
+    /// Matches a new or changed window against the rules, unless it was already matched.
+    fn window_changed(&mut self, window: &Window, requests: &mut dyn RequestSink) -> Result<()> {
+        let previous_title = self
+            .titles
+            .insert(window.id, window.title.clone())
+            .filter(|title| *title != window.title);
+
+        if let Some(followed) = self.followed.get_mut(&window.id) {
+            let moved_to = window
+                .workspace_id
+                .filter(|&id| followed.workspace_id != Some(id) && followed.last_move != Some(id));
+
+            let previous_workspace = followed.workspace_id;
+            followed.workspace_id = window.workspace_id;
+            followed.title.clone_from(&window.title);
+
+            // The window ended up somewhere else, so moving it there again isn't a repeat
+            if followed.last_move.is_some() && followed.last_move != window.workspace_id {
+                followed.last_move = None;
+            }
+
+            if let Some(to) = moved_to {
+                let from_output = self.output_of(previous_workspace).map(str::to_owned);
+                let to_output = self.output_of(Some(to)).map(str::to_owned);
+
+                if to_output.is_some() && from_output != to_output {
+                    info!(
+                        "{} was moved to output {}, following workspaces there",
+                        WindowId(window.id),
+                        to_output.as_deref().unwrap_or_default()
+                    );
+                    if let Some(followed) = self.followed.get_mut(&window.id) {
+                        followed.home_output = to_output;
+                    }
+                }
+
+                if self.config.manual_move == ManualMove::Anchor {
+                    info!(
+                        "{} was moved to workspace {}, no longer following it",
+                        WindowId(window.id),
+                        to
+                    );
+                    self.followed.remove(&window.id);
+                    self.routed.insert(window.id);
+                } else {
+                    debug!("{} was moved to workspace {}", WindowId(window.id), to);
+                }
+            }
+        }
+
+        if self.is_handled(window.id) {
+            if let Some(previous_title) = previous_title {
+                self.reroute(window, previous_title, requests)?;
+            }
+
+            return Ok(());
+        }
+
+        // Toggling Picture-in-Picture quickly opens and closes windows that aren't worth handling
+        if !self.options.match_settle.is_zero()
+            && (self.settling.contains_key(&window.id)
+                || !self.matcher.matching_rules(window).is_empty())
+        {
+            let due = match self.settling.remove(&window.id) {
+                Some((_, due)) => due,
+                None => {
+                    debug!(
+                        "{} matched, waiting {:?} for it to settle",
+                        WindowId(window.id),
+                        self.options.match_settle
+                    );
+                    self.replace_closing(window.id);
+                    Instant::now() + self.options.match_settle
+                }
+            };
+            self.settling.insert(window.id, (window.clone(), due));
+
+            return Ok(());
+        }
+
+        if self.match_window(window, requests)? {
+            info!("{} matched regexs", WindowId(window.id));
+        }
+
+        Ok(())
+    }
+
+    /// Moves an already matched window whose new title gives it another workspace than the previous
+    /// one did, while titles changing in other ways leave it alone.
+    fn reroute(
+        &mut self,
+        window: &Window,
+        previous_title: Option<String>,
+        requests: &mut dyn RequestSink,
+    ) -> Result<()> {
+        if self.picked {
+            return Ok(());
+        }
+
+        let Some((reference, focus)) = self.routing_target(window) else {
+            return Ok(());
+        };
+        if self.paused.load(Ordering::Relaxed) {
+            debug!(
+                "Not rerouting {} to workspace {:?} as moving is paused",
+                WindowId(window.id),
+                reference
+            );
+            return Ok(());
+        }
+
+        let previous = Window {
+            title: previous_title,
+            ..window.clone()
+        };
+        if self
+            .routing_target(&previous)
+            .is_some_and(|(previous, _)| previous == reference)
+        {
+            return Ok(());
+        }
+
+        log_move!(
+            self.options.move_log_level,
+            "Title of {} changed, moving it to workspace {:?}",
+            WindowId(window.id),
+            reference
+        );
+        // A window sent to a workspace of its own no longer follows, or it would be moved back
+        if self.followed.remove(&window.id).is_some() {
+            debug!(
+                "No longer following {}, it was rerouted",
+                WindowId(window.id)
+            );
+        }
+        self.routed.insert(window.id);
+
+        self.move_window(window, reference, focus, requests)
+    }
+
+    /// Workspace the first matching `move` rule sends the window to, and whether focus goes along.
+    fn routing_target(&self, window: &Window) -> Option<(WorkspaceReferenceArg, bool)> {
+        self.matcher
+            .matching_rules(window)
+            .into_iter()
+            .find_map(|rule| {
+                let reference = match rule.action {
+                    RuleAction::Follow | RuleAction::Log => return None,
+                    RuleAction::Move(ref target) => self.workspace_reference(target),
+                    RuleAction::MoveToCapture(ref group) => {
+                        self.workspace_reference(&rule.captured_workspace(window, group)?)
+                    }
+                };
+
+                Some((reference, rule.focus.unwrap_or(self.options.focus)))
+            })
+    }
+
+    /// Forgets about the closes waiting to be reacted to, now that a window replaces them.
+    fn replace_closing(&mut self, window_id: u64) {
+        for (id, _) in self.closing.drain() {
+            debug!(
+                "{} replaced {}, not reacting to its close",
+                WindowId(window_id),
+                WindowId(id)
+            );
+        }
+    }
+
+    /// Whether the window was already matched by a rule.
+    fn is_handled(&self, window_id: u64) -> bool {
+        self.followed.contains_key(&window_id) || self.routed.contains(&window_id)
+    }
+
+    /// Applies the matching rules to a window, returning whether any matched.
+    fn match_window(&mut self, window: &Window, requests: &mut dyn RequestSink) -> Result<bool> {
+        if self.picked {
+            return Ok(false);
+        }
+
+        let mut logged = false;
+        let rules: Vec<_> = self
+            .matcher
+            .matching_rules(window)
+            .into_iter()
+            .filter_map(|rule| {
+                // Followed windows have no target, moved windows have the workspace to move to
+                let target = match rule.action {
+                    RuleAction::Follow => None,
+                    RuleAction::Log => {
+                        logged = true;
+                        return None;
+                    }
+                    RuleAction::Move(ref target) => Some(self.workspace_reference(target)),
+                    RuleAction::MoveToCapture(ref group) => {
+                        match rule.captured_workspace(window, group) {
+                            Some(target) => Some(self.workspace_reference(&target)),
+                            None => {
+                                debug!(
+                                    "{} has no workspace in title group {}",
+                                    WindowId(window.id),
+                                    group
+                                );
+                                return None;
+                            }
+                        }
+                    }
+                };
+
+                Some((
+                    target,
+                    rule.focus.unwrap_or(self.options.focus),
+                    rule.same_workspace_as_focus,
+                    self.timing(rule.debounce, rule.cooldown),
+                ))
+            })
+            .collect();
+
+        // Log rules match the window again on every change, it is only worth telling once
+        if logged {
+            if self.logged.insert(window.id) {
+                info!("{} matched a log rule", WindowId(window.id));
+            } else {
+                debug!("{} matched a log rule again", WindowId(window.id));
+            }
+        }
+
+        if rules.is_empty() {
+            return Ok(false);
+        }
+
+        // Everything niri reported, for bug reports
+        trace!("{} matched: {:?}", WindowId(window.id), window);
+
+        for (target, focus, same_workspace_as_focus, timing) in rules {
+            match target {
+                None => {
+                    if self.followed.contains_key(&window.id) {
+                        continue;
+                    }
+
+                    self.followed.insert(
+                        window.id,
+                        Followed {
+                            same_workspace_as_focus,
+                            timing,
+                            ..Followed::new(window, focus)
+                        },
+                    );
+                    self.apply_match_actions(window.id, requests)?;
+
+                    if let Some(ref target) = self.options.initial_workspace {
+                        let reference = self.workspace_reference(target);
+                        log_move!(
+                            self.options.move_log_level,
+                            "Moving {} to its initial workspace {:?}",
+                            WindowId(window.id),
+                            reference
+                        );
+
+                        self.move_window(window, reference, focus, requests)?;
+                    }
+                }
+                // Left unhandled, so that it is matched again once moving is resumed
+                Some(reference) if self.paused.load(Ordering::Relaxed) => {
+                    debug!(
+                        "Not moving {} to workspace {:?} as moving is paused",
+                        WindowId(window.id),
+                        reference
+                    );
+                }
+                Some(reference) => {
+                    log_move!(
+                        self.options.move_log_level,
+                        "Moving {} to workspace {:?}",
+                        WindowId(window.id),
+                        reference
+                    );
+                    self.routed.insert(window.id);
+
+                    self.move_window(window, reference, focus, requests)?;
+                }
+            }
+        }
+
+        self.notify(NotifyEvent::Match, window.id, window.title.as_deref(), "");
+
+        Ok(true)
+    }
+
+    /// Performs the `on_match_actions` of the config on a newly followed window.
+    fn apply_match_actions(
+        &mut self,
+        window_id: u64,
+        requests: &mut dyn RequestSink,
+    ) -> Result<()> {
+        if self.paused.load(Ordering::Relaxed) {
+            debug!(
+                "Not applying on_match_actions to {} as moving is paused",
+                WindowId(window_id)
+            );
+            return Ok(());
+        }
+
+        for action in self.config.on_match_actions.clone() {
+            debug!("Applying {:?} to {}", action, WindowId(window_id));
+            self.send_action(action.action(window_id), requests)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves a window to a workspace once, for `move` rules and `--initial-workspace`.
+    fn move_window(
+        &mut self,
+        window: &Window,
+        reference: WorkspaceReferenceArg,
+        focus: bool,
+        requests: &mut dyn RequestSink,
+    ) -> Result<()> {
+        if self.paused.load(Ordering::Relaxed) {
+            debug!(
+                "Not moving {} to workspace {:?} as moving is paused",
+                WindowId(window.id),
+                reference
+            );
+            return Ok(());
+        }
+
+        let workspace = match reference {
+            WorkspaceReferenceArg::Id(id) => id.to_string(),
+            WorkspaceReferenceArg::Index(index) => index.to_string(),
+            WorkspaceReferenceArg::Name(ref name) => name.clone(),
+        };
+
+        if focus && let Some(id) = self.resolve_workspace(&reference) {
+            self.expect_focus(id);
+        }
+
+        self.send_action(
+            Action::MoveWindowToWorkspace {
+                window_id: Some(window.id),
+                reference,
+                focus,
+            },
+            requests,
+        )?;
+        self.notify(
+            NotifyEvent::Move,
+            window.id,
+            window.title.as_deref(),
+            &workspace,
+        );
+
+        Ok(())
+    }
+
+    /// Runs the `[notify]` command for an event, if it is configured and wants this event.
+    fn notify(&self, event: NotifyEvent, window_id: u64, title: Option<&str>, workspace: &str) {
+        let Some(ref notify) = self.config.notify else {
+            return;
+        };
+        // Replaying only shows what would be sent to niri
+        if !notify.events.contains(&event) || self.options.replay.is_some() {
+            return;
+        }
+
+        let command: Vec<_> = notify
+            .command
+            .iter()
+            .map(|arg| {
+                arg.replace("{event}", event.name())
+                    .replace("{window_id}", &window_id.to_string())
+                    .replace("{title}", title.unwrap_or_default())
+                    .replace("{workspace}", workspace)
+            })
+            .collect();
+
+        spawn_command(
+            &command,
+            format!("Notify command for {}", WindowId(window_id)),
+        );
+    }
+
+    /// Sends an action to niri, returning whether niri performed it.
+    ///
+    /// Actions that can't be sent because the connection is down are queued until
+    /// [`State::flush_actions`] is called after reconnecting.
+    fn send_action(&mut self, action: Action, requests: &mut dyn RequestSink) -> Result<bool> {
+        self.actions += 1;
+
+        let action = if self.options.emit_script {
+            match self.without_workspace_id(action.clone()) {
+                Some(action) => action,
+                None => {
+                    warn!("Skipping {:?}, its workspace has no name to print", action);
+                    return Ok(false);
+                }
+            }
+        } else {
+            action
+        };
+
+        let reply = match requests.send(Request::Action(action.clone())) {
+            Ok(reply) => reply,
+            Err(err) => {
+                self.queue_action(action);
+                return Err(err.into());
+            }
+        };
+
+        if let Err(err) = reply {
+            warn!("niri failed to perform {:?}: {}", action, err);
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    fn queue_action(&mut self, action: Action) {
+        if self.pending_actions.len() == MAX_PENDING_ACTIONS {
+            let dropped = self.pending_actions.pop_front();
+            debug!("Too many pending actions, dropping {:?}", dropped);
+        }
+
+        self.pending_actions.push_back(action);
+    }
+
+    /// Sends the actions that couldn't be sent while the connection was down, except those about
+    /// windows that are gone since.
+    pub fn flush_actions(&mut self, requests: &mut dyn RequestSink) -> Result<()> {
+        self.pending_actions
+            .retain(|action| match action_window(action) {
+                Some(id) if !self.titles.contains_key(&id) => {
+                    debug!("{} is gone, dropping {:?}", WindowId(id), action);
+                    false
+                }
+                _ => true,
+            });
+
+        if !self.pending_actions.is_empty() {
+            debug!("Sending {} pending actions", self.pending_actions.len());
+        }
+
+        // Actions stay queued until they are sent, in case the connection drops again
+        while let Some(action) = self.pending_actions.front() {
+            if let Err(err) = requests.send(Request::Action(action.clone()))? {
+                warn!("niri failed to perform {:?}: {}", action, err);
+            }
+
+            self.pending_actions.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn window_closed(&mut self, id: u64) {
+        self.titles.remove(&id);
+        self.logged.remove(&id);
+
+        if self.settling.remove(&id).is_some() {
+            debug!("{} closed before it settled", WindowId(id));
+            return;
+        }
+
+        self.routed.remove(&id);
+
+        if let Some(followed) = self.followed.remove(&id) {
+            info!("{} got closed", WindowId(id));
+
+            if self.options.match_settle.is_zero() {
+                self.react_to_close(id, followed.title.as_deref());
+            } else {
+                self.closing.insert(
+                    id,
+                    (followed.title, Instant::now() + self.options.match_settle),
+                );
+            }
+        }
+    }
+
+    /// Runs the notification and the close command for a closed followed window.
+    fn react_to_close(&self, id: u64, title: Option<&str>) {
+        self.notify(NotifyEvent::Close, id, title, "");
+
+        if let Some(ref command) = self.config.on_close_command
+            && self.options.replay.is_none()
+        {
+            spawn_close_command(command, id);
+        }
+    }
+}
+
+/// The window an action is about, for the actions piri sends.
+fn action_window(action: &Action) -> Option<u64> {
+    match *action {
+        Action::MoveWindowToWorkspace { window_id, .. } => window_id,
+        Action::FocusWindow { id } => Some(id),
+        Action::MoveWindowToFloating { id }
+        | Action::MoveWindowToTiling { id }
+        | Action::ToggleWindowRuleOpacity { id }
+        | Action::CenterWindow { id }
+        | Action::FullscreenWindow { id }
+        | Action::MaximizeWindowToEdges { id } => id,
+        _ => None,
+    }
+}
+
+/// Runs the configured close command in the background, passing the window id as the last
+/// argument.
+fn spawn_close_command(command: &[String], window_id: u64) {
+    let mut command = command.to_vec();
+    command.push(window_id.to_string());
+
+    spawn_command(
+        &command,
+        format!("Close command for {}", WindowId(window_id)),
+    );
+}
+
+/// Runs a command in the background, logging how it went under `description`.
+fn spawn_command(command: &[String], description: String) {
+    let Some((program, args)) = command.split_first() else {
+        return;
+    };
+
+    let mut command = Command::new(program);
+    command.args(args);
+
+    thread::spawn(move || match command.status() {
+        Ok(status) if status.success() => debug!("{description} finished"),
+        Ok(status) => warn!("{description} exited with {status}"),
+        Err(err) => warn!("Failed to run {}: {err}", description.to_lowercase()),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+
+    use niri_ipc::{Reply, WindowLayout};
+
+    use super::*;
+    use crate::event_loop;
+    use crate::ipc::Recorder;
+
+    /// Answers like niri would with its windows and workspaces, recording every request.
+    #[derive(Default)]
+    struct Niri {
+        windows: Vec<Window>,
+        workspaces: Vec<Workspace>,
+        requests: Vec<Request>,
+    }
+
+    impl Niri {
+        fn new(windows: Vec<Window>, workspaces: Vec<Workspace>) -> Self {
+            Self {
+                windows,
+                workspaces,
+                requests: Vec::new(),
+            }
+        }
+
+        /// The windows moved by piri and where to, in order.
+        fn moves(&self) -> Vec<(u64, WorkspaceReferenceArg)> {
+            self.requests
+                .iter()
+                .filter_map(|request| match request {
+                    Request::Action(Action::MoveWindowToWorkspace {
+                        window_id: Some(window_id),
+                        reference,
+                        ..
+                    }) => Some((*window_id, reference.clone())),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    impl RequestSink for Niri {
+        fn send(&mut self, request: Request) -> io::Result<Reply> {
+            let response = match request {
+                Request::Windows => Response::Windows(self.windows.clone()),
+                Request::Workspaces => Response::Workspaces(self.workspaces.clone()),
+                _ => Response::Handled,
+            };
+            self.requests.push(request);
+
+            Ok(Ok(response))
+        }
+    }
+
+    fn state(options: Options, config: &str) -> State {
+        let config: Config = toml::from_str(config).unwrap();
+        let matcher = Matcher::from_options(&config, &options).unwrap();
+
+        State::new(options, config, matcher, Arc::new(AtomicBool::new(false)))
+    }
+
+    fn window(id: u64, title: &str, app_id: &str, workspace_id: u64) -> Window {
+        Window {
+            id,
+            title: Some(title.to_owned()),
+            app_id: Some(app_id.to_owned()),
+            pid: None,
+            workspace_id: Some(workspace_id),
+            is_focused: false,
+            is_floating: false,
+            is_urgent: false,
+            layout: WindowLayout {
+                pos_in_scrolling_layout: None,
+                tile_size: (100.0, 100.0),
+                window_size: (100, 100),
+                tile_pos_in_workspace_view: None,
+                window_offset_in_tile: (0.0, 0.0),
+            },
+            focus_timestamp: None,
+        }
+    }
+
+    fn pip(id: u64, workspace_id: u64) -> Window {
+        window(id, "Picture-in-Picture", "firefox", workspace_id)
+    }
+
+    /// Feeds events to the state, like the event loop does.
+    fn replay(state: &mut State, niri: &mut Niri, events: impl IntoIterator<Item = Event>) {
+        for event in events {
+            state.handle_event(event, niri).unwrap();
+        }
+    }
+
+    fn focus(id: u64) -> Event {
+        Event::WorkspaceActivated { id, focused: true }
+    }
+
+    /// Starts a session like the event loop does, scanning the windows niri has before the event
+    /// stream starts with the workspaces and windows.
+    fn start_session(state: &mut State, niri: &mut Niri) {
+        event_loop::scan(state, niri, true).unwrap();
+        let workspaces = niri.workspaces.clone();
+        state
+            .handle_event(Event::WorkspacesChanged { workspaces }, niri)
+            .unwrap();
+        let windows = niri.windows.clone();
+        state
+            .handle_event(Event::WindowsChanged { windows }, niri)
+            .unwrap();
+    }
+
+    fn workspace(id: u64, idx: u8, output: &str) -> Workspace {
+        Workspace {
+            id,
+            idx,
+            name: None,
+            output: Some(output.to_owned()),
+            is_urgent: false,
+            is_active: false,
+            is_focused: false,
+            active_window_id: None,
+        }
+    }
+
+    /// Three workspaces on output A and a single one on output B.
+    fn workspaces() -> Vec<Workspace> {
+        vec![
+            workspace(10, 1, "A"),
+            workspace(11, 2, "A"),
+            workspace(12, 3, "A"),
+            workspace(20, 1, "B"),
+        ]
+    }
+
+    fn offset_state(relative_offset: i32, relative_wrap: bool) -> State {
+        let mut state = state(
+            Options {
+                relative_offset,
+                relative_wrap,
+                ..Options::default()
+            },
+            "",
+        );
+        state.workspaces_changed(workspaces());
+
+        state
+    }
+
+    #[test]
+    fn offset_within_output() {
+        assert_eq!(offset_state(1, false).offset_workspace(10), 11);
+        assert_eq!(offset_state(-1, false).offset_workspace(12), 11);
+        assert_eq!(offset_state(0, false).offset_workspace(11), 11);
+    }
+
+    #[test]
+    fn offset_clamped_to_first_and_last() {
+        assert_eq!(offset_state(1, false).offset_workspace(12), 12);
+        assert_eq!(offset_state(5, false).offset_workspace(10), 12);
+        assert_eq!(offset_state(-1, false).offset_workspace(10), 10);
+        assert_eq!(offset_state(i32::MIN, false).offset_workspace(12), 10);
+        assert_eq!(offset_state(i32::MAX, false).offset_workspace(10), 12);
+    }
+
+    #[test]
+    fn offset_wrapped_past_first_and_last() {
+        assert_eq!(offset_state(1, true).offset_workspace(12), 10);
+        assert_eq!(offset_state(5, true).offset_workspace(12), 11);
+        assert_eq!(offset_state(-1, true).offset_workspace(10), 12);
+        assert_eq!(offset_state(-4, true).offset_workspace(10), 12);
+        assert_eq!(offset_state(i32::MIN, true).offset_workspace(10), 11);
+    }
+
+    #[test]
+    fn offset_on_a_single_workspace_output() {
+        for wrap in [false, true] {
+            assert_eq!(offset_state(1, wrap).offset_workspace(20), 20);
+            assert_eq!(offset_state(-3, wrap).offset_workspace(20), 20);
+        }
+    }
+
+    #[test]
+    fn offset_of_an_unknown_workspace() {
+        assert_eq!(offset_state(1, false).offset_workspace(99), 99);
+    }
+
+    #[test]
+    fn workspace_map_follows_workspace_events() {
+        let mut state = state(Options::default(), "");
+        let mut recorder = Recorder;
+
+        let mut named = workspace(11, 2, "A");
+        named.name = Some("media".to_owned());
+        let mut focused = workspace(10, 1, "A");
+        focused.is_focused = true;
+        focused.is_active = true;
+        let mut active = workspace(20, 1, "B");
+        active.is_active = true;
+        state
+            .handle_event(
+                Event::WorkspacesChanged {
+                    workspaces: vec![focused, named, active],
+                },
+                &mut recorder,
+            )
+            .unwrap();
+        assert_eq!(state.workspaces.len(), 3);
+        assert_eq!(state.focused_workspace, Some(10));
+        assert_eq!(state.workspace_name(11), Some("media"));
+        assert_eq!(state.output_of(Some(20)), Some("B"));
+
+        state
+            .handle_event(
+                Event::WorkspaceActivated {
+                    id: 11,
+                    focused: true,
+                },
+                &mut recorder,
+            )
+            .unwrap();
+        assert_eq!(state.focused_workspace, Some(11));
+        assert!(state.workspaces[&11].is_active && state.workspaces[&11].is_focused);
+        assert!(!state.workspaces[&10].is_active && !state.workspaces[&10].is_focused);
+        assert!(
+            state.workspaces[&20].is_active,
+            "other outputs keep their active workspace"
+        );
+
+        state
+            .handle_event(
+                Event::WorkspaceActivated {
+                    id: 20,
+                    focused: false,
+                },
+                &mut recorder,
+            )
+            .unwrap();
+        assert_eq!(state.focused_workspace, Some(11));
+        assert!(state.workspaces[&11].is_focused);
+
+        state
+            .handle_event(
+                Event::WorkspaceUrgencyChanged {
+                    id: 20,
+                    urgent: true,
+                },
+                &mut recorder,
+            )
+            .unwrap();
+        assert!(state.workspaces[&20].is_urgent);
+
+        state
+            .handle_event(
+                Event::WorkspaceActiveWindowChanged {
+                    workspace_id: 11,
+                    active_window_id: Some(5),
+                },
+                &mut recorder,
+            )
+            .unwrap();
+        assert_eq!(state.workspaces[&11].active_window_id, Some(5));
+
+        // The full list replaces the map, dropping the workspaces that are gone
+        let mut renamed = workspace(11, 1, "B");
+        renamed.name = Some("video".to_owned());
+        renamed.is_focused = true;
+        state
+            .handle_event(
+                Event::WorkspacesChanged {
+                    workspaces: vec![renamed],
+                },
+                &mut recorder,
+            )
+            .unwrap();
+        assert_eq!(state.workspaces.len(), 1);
+        assert_eq!(state.workspace_label(10), None);
+        assert_eq!(state.workspace_label(11).as_deref(), Some("video"));
+        assert_eq!(state.output_of(Some(11)), Some("B"));
+        assert_eq!(state.focused_workspace, Some(11));
+    }
+
+    #[test]
+    fn activation_seen_again_after_reconnect_moves_once() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(vec![pip(1, 10)], workspaces());
+        start_session(&mut state, &mut niri);
+
+        replay(&mut state, &mut niri, [focus(11)]);
+        assert_eq!(niri.moves(), [(1, WorkspaceReferenceArg::Id(11))]);
+
+        // The connection drops and the activation is seen again once reconnected
+        niri.windows = vec![pip(1, 11)];
+        start_session(&mut state, &mut niri);
+        replay(&mut state, &mut niri, [focus(11)]);
+
+        assert_eq!(niri.moves(), [(1, WorkspaceReferenceArg::Id(11))]);
+    }
+
+    #[test]
+    fn activation_seen_again_before_the_window_moved_moves_once() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(vec![pip(1, 10)], workspaces());
+        start_session(&mut state, &mut niri);
+
+        replay(&mut state, &mut niri, [focus(11), focus(11)]);
+
+        assert_eq!(niri.moves(), [(1, WorkspaceReferenceArg::Id(11))]);
+    }
+
+    #[test]
+    fn scanning_again_tracks_windows_once() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(vec![pip(1, 10)], workspaces());
+        start_session(&mut state, &mut niri);
+
+        assert_eq!(state.scan(niri.windows.clone(), &mut niri).unwrap(), 1);
+        start_session(&mut state, &mut niri);
+
+        assert_eq!(state.followed.keys().collect::<Vec<_>>(), [&1]);
+    }
+
+    #[test]
+    fn windows_of_different_browsers_follow_together() {
+        let mut state = state(Options::default(), "browsers = [\"firefox\", \"chromium\"]");
+        let mut niri = Niri::new(
+            vec![
+                pip(1, 10),
+                window(2, "Picture in picture", "chromium", 10),
+                window(3, "Inbox", "chromium", 10),
+            ],
+            workspaces(),
+        );
+        start_session(&mut state, &mut niri);
+
+        replay(&mut state, &mut niri, [focus(11)]);
+
+        let mut moves = niri.moves();
+        moves.sort_unstable_by_key(|&(window_id, _)| window_id);
+        assert_eq!(
+            moves,
+            [
+                (1, WorkspaceReferenceArg::Id(11)),
+                (2, WorkspaceReferenceArg::Id(11))
+            ]
+        );
+    }
+
+    #[test]
+    fn only_firefox_is_followed_by_default() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(
+            vec![pip(1, 10), window(2, "Picture in picture", "chromium", 10)],
+            workspaces(),
+        );
+        start_session(&mut state, &mut niri);
+
+        replay(&mut state, &mut niri, [focus(11)]);
+
+        assert_eq!(niri.moves(), [(1, WorkspaceReferenceArg::Id(11))]);
+    }
+
+    const SAME_WORKSPACE: &str = r#"
+[[rule]]
+app_id = "^mpv$"
+same_workspace_as_focus = true
+"#;
+
+    fn focused_on_first() -> Vec<Workspace> {
+        let mut workspaces = workspaces();
+        workspaces[0].is_focused = true;
+        workspaces
+    }
+
+    #[test]
+    fn same_workspace_as_focus_follows_from_the_left_workspace() {
+        let mut state = state(Options::default(), SAME_WORKSPACE);
+        let mut niri = Niri::new(
+            vec![window(1, "on", "mpv", 10), window(2, "off", "mpv", 11)],
+            focused_on_first(),
+        );
+        start_session(&mut state, &mut niri);
+
+        replay(&mut state, &mut niri, [focus(12)]);
+
+        assert_eq!(niri.moves(), [(1, WorkspaceReferenceArg::Id(12))]);
+    }
+
+    #[test]
+    fn same_workspace_as_focus_leaves_parked_windows() {
+        let mut state = state(Options::default(), SAME_WORKSPACE);
+        let mut niri = Niri::new(vec![window(2, "off", "mpv", 11)], focused_on_first());
+        start_session(&mut state, &mut niri);
+
+        replay(&mut state, &mut niri, [focus(12), focus(10)]);
+
+        assert!(niri.moves().is_empty());
+    }
+
+    #[test]
+    fn windows_follow_from_any_workspace_by_default() {
+        let mut state = state(Options::default(), "[[rule]]\napp_id = \"^mpv$\"");
+        let mut niri = Niri::new(vec![window(2, "off", "mpv", 11)], focused_on_first());
+        start_session(&mut state, &mut niri);
+
+        replay(&mut state, &mut niri, [focus(12)]);
+
+        assert_eq!(niri.moves(), [(2, WorkspaceReferenceArg::Id(12))]);
+    }
+
+    /// Runs a control command, returning the payload of its reply or its error message.
+    fn command(
+        state: &mut State,
+        niri: &mut Niri,
+        line: &str,
+    ) -> Result<serde_json::Value, String> {
+        match state.control(line.parse().unwrap(), niri) {
+            control::Reply::Ok(payload) => Ok(payload),
+            control::Reply::Err(message) => Err(message),
+        }
+    }
+
+    #[test]
+    fn control_reports_versions_and_status() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(vec![pip(1, 10)], workspaces());
+        start_session(&mut state, &mut niri);
+
+        let version = command(&mut state, &mut niri, "version").unwrap();
+        assert_eq!(version["protocol"], control::PROTOCOL_VERSION);
+
+        assert_eq!(
+            command(&mut state, &mut niri, "status").unwrap(),
+            json!({
+                "paused": false,
+                "windows": [{ "id": 1, "title": "Picture-in-Picture", "workspace": "1", "enabled": true }],
+            })
+        );
+        assert_eq!(
+            command(&mut state, &mut niri, "tracked").unwrap(),
+            json!([1])
+        );
+    }
+
+    #[test]
+    fn control_suspends_and_resumes_following() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(vec![pip(1, 10)], workspaces());
+        start_session(&mut state, &mut niri);
+
+        command(&mut state, &mut niri, "follow 1 off").unwrap();
+        replay(&mut state, &mut niri, [focus(11)]);
+        assert!(niri.moves().is_empty());
+
+        command(&mut state, &mut niri, "follow 1 on").unwrap();
+        replay(&mut state, &mut niri, [focus(12)]);
+        assert_eq!(niri.moves(), [(1, WorkspaceReferenceArg::Id(12))]);
+
+        assert_eq!(
+            command(&mut state, &mut niri, "follow 2 on").unwrap_err(),
+            "window#2 is not followed"
+        );
+    }
+
+    #[test]
+    fn control_tracks_and_untracks_windows() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(vec![window(2, "notes", "editor", 10)], workspaces());
+        start_session(&mut state, &mut niri);
+
+        command(&mut state, &mut niri, "track 2").unwrap();
+        assert_eq!(
+            command(&mut state, &mut niri, "tracked").unwrap(),
+            json!([2])
+        );
+        assert_eq!(
+            command(&mut state, &mut niri, "track 2").unwrap_err(),
+            "window#2 is already followed"
+        );
+        assert_eq!(
+            command(&mut state, &mut niri, "track 3").unwrap_err(),
+            "There is no window#3"
+        );
+
+        command(&mut state, &mut niri, "untrack 2").unwrap();
+        assert_eq!(
+            command(&mut state, &mut niri, "tracked").unwrap(),
+            json!([])
+        );
+        // Untracked windows aren't matched again
+        command(&mut state, &mut niri, "rescan").unwrap();
+        replay(&mut state, &mut niri, [focus(11)]);
+        assert!(niri.moves().is_empty());
+    }
+
+    #[test]
+    fn control_pauses_and_resumes_moving() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(vec![pip(1, 10)], workspaces());
+        start_session(&mut state, &mut niri);
+
+        command(&mut state, &mut niri, "pause").unwrap();
+        assert_eq!(
+            command(&mut state, &mut niri, "status").unwrap()["paused"],
+            true
+        );
+        replay(&mut state, &mut niri, [focus(11)]);
+        assert!(niri.moves().is_empty());
+
+        command(&mut state, &mut niri, "resume").unwrap();
+        replay(&mut state, &mut niri, [focus(12)]);
+        assert_eq!(niri.moves(), [(1, WorkspaceReferenceArg::Id(12))]);
+    }
+
+    #[test]
+    fn control_rescans_and_explains() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(Vec::new(), workspaces());
+        start_session(&mut state, &mut niri);
+
+        niri.windows = vec![pip(1, 10), window(2, "notes", "editor", 10)];
+        assert_eq!(
+            command(&mut state, &mut niri, "rescan").unwrap(),
+            json!({ "matched": 1 })
+        );
+        assert_eq!(
+            command(&mut state, &mut niri, "rescan").unwrap(),
+            json!({ "matched": 0 })
+        );
+
+        assert_eq!(
+            command(&mut state, &mut niri, "explain 1").unwrap()["tracked"],
+            true
+        );
+        assert_eq!(
+            command(&mut state, &mut niri, "explain 2").unwrap()["tracked"],
+            false
+        );
+        assert!(command(&mut state, &mut niri, "explain 3").is_err());
+    }
+
+    #[test]
+    fn control_dumps_the_state() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(vec![pip(1, 10)], workspaces());
+        start_session(&mut state, &mut niri);
+
+        let dump = command(&mut state, &mut niri, "dump-state").unwrap();
+        assert_eq!(dump["followed"][0]["id"], 1);
+        assert_eq!(dump["workspaces"].as_array().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn control_reloads_the_config() {
+        let path = env::temp_dir().join(format!("piri-test-{}-reload.toml", std::process::id()));
+        fs::write(&path, "[[rule]]\napp_id = \"^editor$\"").unwrap();
+        let options = Options {
+            config_path: Some(path.clone()),
+            ..Options::default()
+        };
+        let mut state = state(options, "");
+        let mut niri = Niri::new(vec![window(2, "notes", "editor", 10)], workspaces());
+        start_session(&mut state, &mut niri);
+        assert!(state.followed.is_empty());
+
+        let reloaded = command(&mut state, &mut niri, "reload");
+        fs::remove_file(&path).unwrap();
+        reloaded.unwrap();
+
+        command(&mut state, &mut niri, "rescan").unwrap();
+        assert_eq!(state.followed.keys().collect::<Vec<_>>(), [&2]);
+    }
+
+    const CAPTURE: &str = r#"
+[[rule]]
+title = "ws:(?<ws>\\w+)"
+action = "move-to-capture"
+capture = "ws"
+"#;
+
+    fn retitled(window: &Window, title: &str) -> Event {
+        Event::WindowOpenedOrChanged {
+            window: Window {
+                title: Some(title.to_owned()),
+                ..window.clone()
+            },
+        }
+    }
+
+    #[test]
+    fn title_change_routes_a_window() {
+        let mut state = state(Options::default(), CAPTURE);
+        let notes = window(3, "notes", "editor", 10);
+        let mut niri = Niri::new(vec![notes.clone()], workspaces());
+        start_session(&mut state, &mut niri);
+        assert!(niri.moves().is_empty());
+
+        replay(&mut state, &mut niri, [retitled(&notes, "notes ws:media")]);
+        assert_eq!(
+            niri.moves(),
+            [(3, WorkspaceReferenceArg::Name("media".to_owned()))]
+        );
+    }
+
+    #[test]
+    fn title_change_to_another_workspace_routes_again() {
+        let mut state = state(Options::default(), CAPTURE);
+        let notes = window(3, "notes ws:media", "editor", 10);
+        let mut niri = Niri::new(vec![notes.clone()], workspaces());
+        start_session(&mut state, &mut niri);
+
+        replay(
+            &mut state,
+            &mut niri,
+            [
+                // Same workspace, nothing to do
+                retitled(&notes, "notes ws:media (edited)"),
+                retitled(&notes, "notes ws:12"),
+                retitled(&notes, "notes"),
+            ],
+        );
+
+        assert_eq!(
+            niri.moves(),
+            [
+                (3, WorkspaceReferenceArg::Name("media".to_owned())),
+                (3, WorkspaceReferenceArg::Id(12)),
+            ]
+        );
+    }
+
+    #[test]
+    fn rerouted_window_stops_following() {
+        let config = format!("rule_evaluation = \"all\"\n[[rule]]\napp_id = \"^mpv$\"\n{CAPTURE}");
+        let mut state = state(Options::default(), &config);
+        let video = window(1, "video", "mpv", 10);
+        let mut niri = Niri::new(vec![video.clone()], workspaces());
+        start_session(&mut state, &mut niri);
+        assert!(state.followed.contains_key(&1));
+
+        replay(
+            &mut state,
+            &mut niri,
+            [retitled(&video, "video ws:media"), focus(11)],
+        );
+
+        assert!(!state.followed.contains_key(&1));
+        assert_eq!(
+            niri.moves(),
+            [(1, WorkspaceReferenceArg::Name("media".to_owned()))]
+        );
+    }
+
+    #[test]
+    fn windows_are_matched_again_under_new_ids_after_reconnect() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(
+            vec![pip(1, 10), window(2, "notes", "editor", 10)],
+            workspaces(),
+        );
+        start_session(&mut state, &mut niri);
+        assert_eq!(state.followed.keys().collect::<Vec<_>>(), [&1]);
+
+        // niri restarted, the same windows come back with other ids
+        niri.windows = vec![window(7, "notes", "editor", 10), pip(8, 10)];
+        start_session(&mut state, &mut niri);
+        assert_eq!(state.followed.keys().collect::<Vec<_>>(), [&8]);
+        assert!(!state.titles.contains_key(&1));
+
+        replay(&mut state, &mut niri, [focus(11)]);
+        assert_eq!(niri.moves(), [(8, WorkspaceReferenceArg::Id(11))]);
+    }
+
+    #[test]
+    fn pending_actions_for_stale_ids_are_dropped_after_reconnect() {
+        let mut state = state(Options::default(), "");
+        let mut niri = Niri::new(vec![pip(1, 10)], workspaces());
+        start_session(&mut state, &mut niri);
+        state.queue_action(Action::MoveWindowToWorkspace {
+            window_id: Some(1),
+            reference: WorkspaceReferenceArg::Id(11),
+            focus: false,
+        });
+
+        niri.windows = vec![pip(8, 10)];
+        start_session(&mut state, &mut niri);
+
+        assert!(niri.moves().is_empty());
+        assert!(state.pending_actions.is_empty());
+    }
+
+    /// Two windows on outputs A and B while workspaces switch on both at once.
+    fn burst_state(burst_target: BurstTarget) -> (State, Niri) {
+        let options = Options {
+            activation_burst: Duration::from_millis(50),
+            burst_target,
+            ..Options::default()
+        };
+        let mut workspaces = workspaces();
+        workspaces.push(workspace(21, 2, "B"));
+        let mut state = state(options, "");
+        let mut niri = Niri::new(vec![pip(1, 10), pip(2, 20)], workspaces);
+        start_session(&mut state, &mut niri);
+
+        let activations = [
+            Event::WorkspaceActivated {
+                id: 11,
+                focused: false,
+            },
+            focus(21),
+        ];
+        replay(&mut state, &mut niri, activations);
+
+        (state, niri)
+    }
+
+    /// Lets the burst of activations settle, as if its delay passed.
+    fn settle_burst(state: &mut State, niri: &mut Niri) {
+        if let Some((_, due)) = state.burst.as_mut() {
+            *due = Instant::now();
+        }
+        state.run_timers(niri).unwrap();
+    }
+
+    #[test]
+    fn burst_moves_windows_on_their_monitor() {
+        let (mut state, mut niri) = burst_state(BurstTarget::WindowMonitor);
+        assert!(niri.moves().is_empty(), "bursts wait to settle");
+
+        settle_burst(&mut state, &mut niri);
+
+        let mut moves = niri.moves();
+        moves.sort_unstable_by_key(|&(window_id, _)| window_id);
+        assert_eq!(
+            moves,
+            [
+                (1, WorkspaceReferenceArg::Id(11)),
+                (2, WorkspaceReferenceArg::Id(21))
+            ]
+        );
+    }
+
+    #[test]
+    fn burst_moves_windows_to_the_focused_monitor() {
+        let (mut state, mut niri) = burst_state(BurstTarget::FocusedMonitor);
+
+        settle_burst(&mut state, &mut niri);
+
+        let mut moves = niri.moves();
+        moves.sort_unstable_by_key(|&(window_id, _)| window_id);
+        assert_eq!(
+            moves,
+            [
+                (1, WorkspaceReferenceArg::Id(21)),
+                (2, WorkspaceReferenceArg::Id(21))
+            ]
+        );
+    }
+
+    #[test]
+    fn burst_in_the_paused_overview_waits_for_it_to_close() {
+        let (mut state, mut niri) = burst_state(BurstTarget::WindowMonitor);
+        state.options.overview_behavior = OverviewBehavior::Pause;
+        replay(
+            &mut state,
+            &mut niri,
+            [Event::OverviewOpenedOrClosed { is_open: true }],
+        );
+
+        settle_burst(&mut state, &mut niri);
+        assert!(niri.moves().is_empty());
+
+        replay(
+            &mut state,
+            &mut niri,
+            [Event::OverviewOpenedOrClosed { is_open: false }],
+        );
+        let mut moves = niri.moves();
+        moves.sort_unstable_by_key(|&(window_id, _)| window_id);
+        assert_eq!(
+            moves,
+            [
+                (1, WorkspaceReferenceArg::Id(11)),
+                (2, WorkspaceReferenceArg::Id(21))
+            ],
+            "every monitor follows its activation"
+        );
+    }
+
+    #[test]
+    fn numeric_targets_of_windows_open_at_startup_are_ids() {
+        let options = Options {
+            initial_workspace: Some("12".to_owned()),
+            ..Options::default()
+        };
+        let config = "[[rule]]\napp_id = \"^mpv$\"\naction = \"move\"\ntarget_workspace = \"11\"\n\
+                      [[rule]]\napp_id = \"^editor$\"";
+        let mut state = state(options, config);
+        // No workspace event came yet, the scan fetches them
+        let mut niri = Niri::new(
+            vec![
+                window(1, "video", "mpv", 10),
+                window(2, "notes", "editor", 10),
+            ],
+            workspaces(),
+        );
+        event_loop::scan(&mut state, &mut niri, true).unwrap();
+
+        let mut moves = niri.moves();
+        moves.sort_unstable_by_key(|&(window_id, _)| window_id);
+        assert_eq!(
+            moves,
+            [
+                (1, WorkspaceReferenceArg::Id(11)),
+                (2, WorkspaceReferenceArg::Id(12))
+            ]
+        );
+    }
+
+    #[test]
+    fn numeric_targets_without_such_workspace_are_names() {
+        let config = "[[rule]]\napp_id = \"^mpv$\"\naction = \"move\"\ntarget_workspace = \"3\"";
+        let mut state = state(Options::default(), config);
+        let mut niri = Niri::new(vec![window(1, "video", "mpv", 10)], workspaces());
+        event_loop::scan(&mut state, &mut niri, true).unwrap();
+
+        assert_eq!(
+            niri.moves(),
+            [(1, WorkspaceReferenceArg::Name("3".to_owned()))]
+        );
+    }
+
+    #[test]
+    fn script_names_the_workspaces_it_moves_to() {
+        let options = Options {
+            emit_script: true,
+            ..Options::default()
+        };
+        let mut state = state(options, "");
+        let mut workspaces = workspaces();
+        workspaces[1].name = Some("media".to_owned());
+        let mut niri = Niri::new(vec![pip(1, 10), pip(2, 20)], workspaces);
+        start_session(&mut state, &mut niri);
+
+        replay(&mut state, &mut niri, [focus(11)]);
+        assert_eq!(
+            niri.moves(),
+            [(1, WorkspaceReferenceArg::Name("media".to_owned()))]
+        );
+    }
+
+    #[test]
+    fn script_skips_moves_to_unnamed_workspaces() {
+        let options = Options {
+            emit_script: true,
+            ..Options::default()
+        };
+        let mut state = state(options, "");
+        let mut niri = Niri::new(vec![pip(1, 10), pip(2, 20)], workspaces());
+        start_session(&mut state, &mut niri);
+
+        replay(&mut state, &mut niri, [focus(12)]);
+        assert!(
+            niri.moves().is_empty(),
+            "an index would be of the focused output"
+        );
+    }
+}